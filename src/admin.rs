@@ -0,0 +1,67 @@
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    Pause,
+    Resume,
+    Status,
+    Reconnects,
+    /// Reload the config, as on SIGHUP.
+    Reload,
+    /// Replace the socket mode connection.
+    Reconnect,
+}
+
+/// Parses `<@bot> <command>` style messages. In direct messages (`direct`)
+/// the mention is optional.
+pub fn parse_admin_command(text: &str, bot_user_id: &str, direct: bool) -> Option<AdminCommand> {
+    let text = text.trim();
+    let rest = match text.strip_prefix(&format!("<@{}>", bot_user_id)) {
+        Some(rest) => rest,
+        None if direct => text,
+        None => return None,
+    };
+    match rest.trim().to_lowercase().as_str() {
+        "pause" => Some(AdminCommand::Pause),
+        "resume" => Some(AdminCommand::Resume),
+        "status" => Some(AdminCommand::Status),
+        "reconnects" => Some(AdminCommand::Reconnects),
+        "reload" => Some(AdminCommand::Reload),
+        "reconnect" => Some(AdminCommand::Reconnect),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_after_a_mention() {
+        assert_eq!(
+            parse_admin_command(" <@UBOT>  Pause ", "UBOT", false),
+            Some(AdminCommand::Pause)
+        );
+        assert_eq!(
+            parse_admin_command("<@UBOT> reconnects", "UBOT", false),
+            Some(AdminCommand::Reconnects)
+        );
+        assert_eq!(
+            parse_admin_command("<@UBOT> pause now", "UBOT", false),
+            None
+        );
+        assert_eq!(parse_admin_command("<@UOTHER> pause", "UBOT", false), None);
+        assert_eq!(parse_admin_command("pause", "UBOT", false), None);
+    }
+
+    #[test]
+    fn mention_is_optional_in_direct_messages() {
+        assert_eq!(
+            parse_admin_command("reload", "UBOT", true),
+            Some(AdminCommand::Reload)
+        );
+        assert_eq!(
+            parse_admin_command("<@UBOT> reconnect", "UBOT", true),
+            Some(AdminCommand::Reconnect)
+        );
+        assert_eq!(parse_admin_command("hello", "UBOT", true), None);
+    }
+}
@@ -0,0 +1,1846 @@
+use crate::admin::{parse_admin_command, AdminCommand};
+use crate::bounded_set::BoundedSet;
+use crate::coalesce::{combine, Coalescer, Push};
+use crate::config::{ChannelType, CooldownKey, EchoFormat, RawConfig};
+use crate::dedup::ContentDedup;
+use crate::echo::{
+    echo_length, echo_text, in_echo_scope, message_to_echo, render_template, truncate_chars,
+};
+use crate::hooks::{AfterSend, NoopAfterSend, NoopViewClosed, ViewClosedHandler};
+use crate::interactive::InteractivePayload;
+use crate::ordering::KeyedQueue;
+use crate::reconnect::{HandshakeGate, ReconnectHistory};
+use crate::slack::{PostMessageRequest, SlackClient, SlackError, SLACK_API_BASE};
+use crate::socket_mode::SocketWriter;
+use crate::stats::{EventTypeCounts, FrameSizeHistogram};
+use crate::throttle::{Cooldowns, LeakyBucket};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// Number of message timestamps remembered to avoid echoing a message twice.
+const ECHOED_MESSAGES_CAPACITY: usize = 1024;
+
+/// Number of event ids remembered to process redelivered events once.
+const DELIVERED_EVENTS_CAPACITY: usize = 1024;
+
+/// Settings which are only read at startup and are not changed by
+/// [`App::reload_config`].
+const STARTUP_SETTINGS: &[&str] = &[
+    "message_parse",
+    "reconnect_history_size",
+    "transient_retries",
+    "response_sample_seed",
+    "content_dedup_window",
+    "max_echoes_per_minute",
+    "max_concurrent_handshakes",
+];
+
+/// Number of threads remembered for [`RawConfig::echo_once_per_thread`].
+const REPLIED_THREADS_CAPACITY: usize = 1024;
+
+/// The `chat.postMessage` request of an echo, formatted as configured.
+fn echo_message<'a>(
+    config: &'a RawConfig,
+    slack_client: &SlackClient,
+    channel: &'a str,
+    text: &'a str,
+) -> PostMessageRequest<'a> {
+    PostMessageRequest {
+        mrkdwn: config.echo_format != EchoFormat::Plain,
+        icon_emoji: config.icon_emoji.as_deref(),
+        ..slack_client.message(channel, text)
+    }
+}
+
+/// Identifies the thread of `message` in `channel`, for
+/// [`RawConfig::echo_once_per_thread`]. A top level message starts its own
+/// thread.
+fn thread_key(channel: &str, message: &serde_json::Value) -> Option<String> {
+    message
+        .get("thread_ts")
+        .or_else(|| message.get("ts"))
+        .and_then(|v| v.as_str())
+        .map(|thread_ts| format!("{}/{}", channel, thread_ts))
+}
+
+/// What became of an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The echo was posted as the message `ts`.
+    Echoed { ts: String },
+    /// A message other than an echo was sent, e.g. the answer to an admin
+    /// command.
+    Replied,
+    /// Something other than sending a message was done, e.g. joining a
+    /// channel.
+    Handled,
+    /// Nothing to do, e.g. a filtered message or an unhandled event type.
+    Ignored,
+    /// Posting the reply failed.
+    Failed,
+    /// The echo was buffered to be posted as part of a combined message.
+    Coalesced,
+    /// The event was delivered before, or its content was just echoed.
+    Duplicate,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Echoed { .. } => "echoed",
+            Self::Replied => "replied",
+            Self::Handled => "handled",
+            Self::Ignored => "ignored",
+            Self::Failed => "failed",
+            Self::Coalesced => "coalesced",
+            Self::Duplicate => "duplicate",
+        }
+    }
+
+    /// Whether a message was sent in response.
+    pub fn replied(&self) -> bool {
+        matches!(self, Self::Echoed { .. } | Self::Replied)
+    }
+
+    /// ts of the posted echo, if any.
+    pub fn ts(&self) -> Option<&str> {
+        match self {
+            Self::Echoed { ts } => Some(ts),
+            _ => None,
+        }
+    }
+}
+
+/// A workspace the bot is installed in.
+pub struct Installation {
+    pub slack_client: SlackClient,
+    pub bot_user_id: Option<String>,
+    /// `bot_id` of this app, which messages it posted carry.
+    pub bot_id: Option<String>,
+}
+
+impl Installation {
+    /// The bot `UBOT` (`BBOT`), without calling `auth.test`.
+    #[cfg(test)]
+    fn for_test(config: &RawConfig, api_base: &str) -> Self {
+        Self {
+            slack_client: SlackClient {
+                token: config.user_oauth_token.clone(),
+                parse: config.message_parse,
+                transient_retries: 0,
+                api_base: api_base.to_string(),
+            },
+            bot_user_id: Some("UBOT".to_string()),
+            bot_id: Some("BBOT".to_string()),
+        }
+    }
+
+    /// Identifies the bot with `auth.test` and logs the granted scopes.
+    async fn new(token: String, config: &RawConfig) -> Self {
+        let slack_client = SlackClient {
+            token,
+            parse: config.message_parse,
+            transient_retries: config.transient_retries,
+            api_base: SLACK_API_BASE.to_string(),
+        };
+
+        let auth = slack_client
+            .auth_test()
+            .await
+            .unwrap_or_else(|e| panic!("auth.test failed: {}", e));
+        match &auth.scopes {
+            Some(scopes) => {
+                let mut sorted: Vec<_> = scopes.iter().map(String::as_str).collect();
+                sorted.sort_unstable();
+                tracing::info!("Granted scopes: {}", sorted.join(","));
+                let missing: Vec<_> = config
+                    .needed_scopes()
+                    .into_iter()
+                    .filter(|scope| !scopes.contains(*scope))
+                    .collect();
+                if config.warn_missing_scopes && !missing.is_empty() {
+                    tracing::warn!(
+                        "The token lacks scopes needed by enabled features: {}",
+                        missing.join(",")
+                    );
+                }
+            }
+            None => tracing::info!("auth.test didn't report the granted scopes"),
+        }
+
+        Self {
+            slack_client,
+            bot_user_id: auth.user_id,
+            bot_id: auth.bot_id,
+        }
+    }
+}
+
+/// State shared by all connections.
+pub struct App {
+    /// Swapped as a whole by [`App::reload_config`].
+    config: RwLock<Arc<RawConfig>>,
+    /// Installation of `SLACK_USER_OAUTH_TOKEN`.
+    pub installation: Installation,
+    /// Installations by team id in multi-team mode, see
+    /// [`RawConfig::team_tokens`].
+    pub team_installations: HashMap<String, Installation>,
+    pub paused: AtomicBool,
+    pub frame_sizes: FrameSizeHistogram,
+    pub event_types: EventTypeCounts,
+    /// Pins and stars by event and item type, e.g. `pin_added:message`.
+    pub engagement: EventTypeCounts,
+    pub reconnects: Mutex<ReconnectHistory>,
+    /// Serializes event handling per channel.
+    pub channel_queue: Arc<KeyedQueue>,
+    /// Writes frames to the current socket mode connection.
+    pub socket_writer: SocketWriter,
+    echoed: Mutex<BoundedSet>,
+    /// `channel/thread_ts` of threads the bot replied in.
+    replied_threads: Mutex<BoundedSet>,
+    /// Length in characters echoes are truncated to, lowered to
+    /// [`RawConfig::safe_message_length`] once Slack rejected an echo as too
+    /// long.
+    max_message_length: AtomicUsize,
+    /// Decides which messages are replied to, see
+    /// [`RawConfig::response_sample_rate`].
+    sampler: Mutex<StdRng>,
+    /// Set if [`RawConfig::content_dedup_window`] is.
+    content_dedup: Option<ContentDedup>,
+    /// Set if [`RawConfig::max_echoes_per_minute`] is.
+    echo_limit: Option<LeakyBucket>,
+    /// See [`RawConfig::reply_cooldown`].
+    reply_cooldowns: Cooldowns,
+    /// Number of echoes dropped by `echo_limit`.
+    throttled_echoes: AtomicU64,
+    /// End of the warm-up after the latest connection, see
+    /// [`RawConfig::warmup`].
+    warm_until: Mutex<Option<Instant>>,
+    /// Asks the current connection to be replaced, see
+    /// [`AdminCommand::Reconnect`].
+    pub reconnect_requests: (
+        async_std::channel::Sender<()>,
+        async_std::channel::Receiver<()>,
+    ),
+    /// Gates opening connections, see
+    /// [`RawConfig::max_concurrent_handshakes`].
+    pub handshakes: HandshakeGate,
+    /// Run after every posted echo, see [`Self::with_after_send`].
+    after_send: Box<dyn AfterSend>,
+    /// Reacts to dismissed modals, see [`Self::with_view_closed_handler`].
+    on_view_closed: Box<dyn ViewClosedHandler>,
+    /// Echoes waiting to be posted together, see
+    /// [`RawConfig::coalesce_window`].
+    coalescer: Coalescer,
+    /// `event_id`s of processed events.
+    delivered_events: Mutex<BoundedSet>,
+}
+
+impl App {
+    pub async fn new(config: RawConfig) -> Self {
+        let installation = Installation::new(config.user_oauth_token.clone(), &config).await;
+        let mut team_installations = HashMap::new();
+        for (team_id, token) in &config.team_tokens {
+            tracing::info!("Authenticating for team {}", team_id);
+            team_installations.insert(
+                team_id.clone(),
+                Installation::new(token.clone(), &config).await,
+            );
+        }
+        Self::with_installations(config, installation, team_installations)
+    }
+
+    /// Runs `hook` after every posted echo instead of doing nothing.
+    pub fn with_after_send(mut self, hook: impl AfterSend + 'static) -> Self {
+        self.after_send = Box::new(hook);
+        self
+    }
+
+    /// Passes dismissed modals to `handler` instead of ignoring them.
+    pub fn with_view_closed_handler(mut self, handler: impl ViewClosedHandler + 'static) -> Self {
+        self.on_view_closed = Box::new(handler);
+        self
+    }
+
+    fn with_installations(
+        config: RawConfig,
+        installation: Installation,
+        team_installations: HashMap<String, Installation>,
+    ) -> Self {
+        Self {
+            reconnects: Mutex::new(ReconnectHistory::new(config.reconnect_history_size)),
+            content_dedup: config.content_dedup_window.map(ContentDedup::new),
+            echo_limit: config.max_echoes_per_minute.map(LeakyBucket::per_minute),
+            handshakes: HandshakeGate::new(config.max_concurrent_handshakes),
+            throttled_echoes: AtomicU64::new(0),
+            reply_cooldowns: Cooldowns::default(),
+            sampler: Mutex::new(match config.response_sample_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
+            config: RwLock::new(Arc::new(config)),
+            installation,
+            team_installations,
+            paused: AtomicBool::new(false),
+            frame_sizes: FrameSizeHistogram::default(),
+            event_types: EventTypeCounts::default(),
+            engagement: EventTypeCounts::default(),
+            channel_queue: Arc::default(),
+            socket_writer: SocketWriter::default(),
+            echoed: Mutex::new(BoundedSet::new(ECHOED_MESSAGES_CAPACITY)),
+            replied_threads: Mutex::new(BoundedSet::new(REPLIED_THREADS_CAPACITY)),
+            max_message_length: AtomicUsize::new(usize::MAX),
+            delivered_events: Mutex::new(BoundedSet::new(DELIVERED_EVENTS_CAPACITY)),
+            warm_until: Mutex::new(None),
+            reconnect_requests: async_std::channel::bounded(1),
+            coalescer: Coalescer::default(),
+            after_send: Box::new(NoopAfterSend),
+            on_view_closed: Box::new(NoopViewClosed),
+        }
+    }
+
+    /// An app of `config` whose installation is the bot `UBOT` (`BBOT`),
+    /// without calling `auth.test`.
+    #[cfg(test)]
+    pub fn for_test(config: RawConfig) -> Arc<Self> {
+        Self::for_test_with_api(config, SLACK_API_BASE)
+    }
+
+    /// [`Self::for_test`] calling the Web API at `api_base`.
+    #[cfg(test)]
+    pub fn for_test_with_api(config: RawConfig, api_base: &str) -> Arc<Self> {
+        let installation = Installation::for_test(&config, api_base);
+        Arc::new(Self::with_installations(
+            config,
+            installation,
+            HashMap::new(),
+        ))
+    }
+
+    /// The current config. Handlers keep using the config they started with
+    /// while it is reloaded.
+    pub fn config(&self) -> Arc<RawConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// Loads the config again and swaps it in, returning the names of the
+    /// changed settings.
+    ///
+    /// The app-level token is used from the next connection on. Changes to the
+    /// user OAuth token and [`STARTUP_SETTINGS`] need a restart.
+    pub fn reload_config(&self) -> Result<Vec<&'static str>, String> {
+        Ok(self.swap_config(RawConfig::load()?))
+    }
+
+    /// Swaps in `new`, see [`Self::reload_config`].
+    fn swap_config(&self, new: RawConfig) -> Vec<&'static str> {
+        let old = self.config();
+        let changed = old.changed_settings(&new);
+        tracing::info!("Reloaded config, changed: {:?}", changed);
+        if new.app_level_token != old.app_level_token {
+            tracing::info!("The app-level token changed, it is used from the next connection");
+        }
+        if new.user_oauth_token != old.user_oauth_token || new.team_tokens != old.team_tokens {
+            tracing::warn!("The user OAuth tokens changed, restart to use them");
+        }
+        let restart: Vec<_> = changed
+            .iter()
+            .filter(|name| STARTUP_SETTINGS.contains(name))
+            .collect();
+        if !restart.is_empty() {
+            tracing::warn!("Restart to apply {:?}", restart);
+        }
+        *self.config.write().unwrap() = Arc::new(new);
+        changed
+    }
+
+    /// Starts the warm-up of a new connection, if enabled.
+    pub fn start_warmup(&self) {
+        if let Some(warmup) = self.config().warmup {
+            *self.warm_until.lock().unwrap() = Some(Instant::now() + warmup);
+        }
+    }
+
+    /// Waits for the warm-up to end, if it is on.
+    ///
+    /// After (re)connecting Slack may deliver a burst of buffered events. They
+    /// are acked right away, but their replies wait for the end of the warm-up
+    /// plus a random delay of up to the warm-up again, so they trickle out
+    /// instead of going out at once.
+    async fn warm_up(&self) {
+        let (remaining, warmup) = match self.warmup_remaining(Instant::now()) {
+            Some(warming_up) => warming_up,
+            None => return,
+        };
+        let delay = remaining + rand::thread_rng().gen_range(Duration::ZERO..=warmup);
+        tracing::debug!("Warming up, delaying reply by {:?}", delay);
+        async_std::task::sleep(delay).await;
+    }
+
+    /// Time left of the warm-up at `now` and the warm-up length, unless it is
+    /// over or off.
+    pub fn warmup_remaining(&self, now: Instant) -> Option<(Duration, Duration)> {
+        let warm_until = (*self.warm_until.lock().unwrap())?;
+        let warmup = self.config().warmup?;
+        let remaining = warm_until.saturating_duration_since(now);
+        (!remaining.is_zero()).then_some((remaining, warmup))
+    }
+
+    /// Records a delivery of the event `event_id` and returns `true` unless it
+    /// was already processed.
+    ///
+    /// Slack redelivers events it didn't see acknowledged in time, with the
+    /// number of the attempt and the reason (e.g. `timeout`) in the
+    /// `retry_attempt`/`retry_reason` envelope fields.
+    pub fn first_delivery(
+        &self,
+        event_id: Option<&str>,
+        retry_attempt: u32,
+        retry_reason: Option<&str>,
+    ) -> bool {
+        if retry_attempt > 0 {
+            tracing::info!(
+                "Redelivery #{} of event {}: {}",
+                retry_attempt,
+                event_id.unwrap_or("-"),
+                retry_reason.unwrap_or("unknown reason")
+            );
+        }
+        let event_id = match event_id {
+            Some(event_id) => event_id,
+            None => return true,
+        };
+        if !self.delivered_events.lock().unwrap().insert(event_id) {
+            tracing::debug!("Ignoring already processed event {}", event_id);
+            return false;
+        }
+        true
+    }
+
+    /// Team an Events API payload is for: the first team of its
+    /// `authorizations` (or its `team_id`) the bot is installed in, else the
+    /// first team named, so it is reported as unknown.
+    pub fn authorized_team(&self, payload: &serde_json::Value) -> Option<String> {
+        let teams = payload
+            .get("authorizations")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|authorization| authorization.get("team_id"))
+            .chain(payload.get("team_id"))
+            .filter_map(|v| v.as_str());
+        let mut first = None;
+        for team in teams {
+            if self.team_installations.contains_key(team) {
+                return Some(team.to_string());
+            }
+            first.get_or_insert(team);
+        }
+        first.map(String::from)
+    }
+
+    /// The installation to handle events of `team_id` with: the only one, or
+    /// in multi-team mode the one of that team.
+    fn installation(&self, team_id: Option<&str>) -> Option<&Installation> {
+        if self.team_installations.is_empty() {
+            return Some(&self.installation);
+        }
+        self.team_installations.get(team_id?)
+    }
+
+    /// Dispatches the inner `event` of an (already acknowledged) Events API
+    /// envelope by its type.
+    ///
+    /// `team_id` is the team the event is authorized for, from the
+    /// `authorizations` of the envelope.
+    pub async fn handle_event(
+        self: &Arc<Self>,
+        event: &serde_json::Value,
+        team_id: Option<&str>,
+    ) -> Outcome {
+        let event_type = event
+            .get("type")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+        let channel_type = match event_type {
+            "message" => ChannelType::of_event(event),
+            _ => None,
+        };
+        // `message` events are recorded by their compound type, e.g.
+        // `message.im`, so DM and channel traffic can be told apart.
+        self.event_types.record(
+            channel_type
+                .map(ChannelType::message_event_type)
+                .unwrap_or(event_type),
+        );
+        let installation = match self.installation(team_id) {
+            Some(installation) => installation,
+            None => {
+                tracing::warn!(
+                    "No installation for team {}, ignoring {} event",
+                    team_id.unwrap_or("-"),
+                    event_type
+                );
+                return Outcome::Ignored;
+            }
+        };
+        match event_type {
+            "app_mention" => {
+                let channel_type = event
+                    .get("channel")
+                    .and_then(|v| v.as_str())
+                    .and_then(ChannelType::of_channel_id);
+                self.handle_message(installation, event, channel_type, team_id)
+                    .await
+            }
+            "message" => {
+                self.handle_message(installation, event, channel_type, team_id)
+                    .await
+            }
+            "team_join" => self.handle_team_join(installation, event).await,
+            "channel_created" => self.handle_channel_created(installation, event).await,
+            "pin_added" | "star_added" => self.handle_engagement(event_type, event),
+            _ => {
+                tracing::debug!("Ignoring inner event of type {}", event_type);
+                Outcome::Ignored
+            }
+        }
+    }
+
+    /// Handles the payload of an (already acknowledged) interactive envelope.
+    pub async fn handle_interactive(&self, payload: &InteractivePayload) -> Outcome {
+        match payload {
+            InteractivePayload::ViewClosed(closed) => {
+                tracing::info!(
+                    "View {} ({}) closed by {}",
+                    closed.view.id,
+                    closed.view.callback_id.as_deref().unwrap_or("-"),
+                    closed.user.id
+                );
+                tracing::debug!(
+                    "Closed view private_metadata={:?} is_cleared={}",
+                    closed.view.private_metadata,
+                    closed.is_cleared
+                );
+                match self.on_view_closed.view_closed(closed).await {
+                    Ok(()) => Outcome::Handled,
+                    Err(e) => {
+                        tracing::warn!("view_closed handler failed: {}", e);
+                        Outcome::Failed
+                    }
+                }
+            }
+            InteractivePayload::Other => {
+                tracing::debug!("Ignoring interactive payload");
+                Outcome::Ignored
+            }
+        }
+    }
+
+    /// Handles `app_mention` and `message` events. Direct messages may carry
+    /// admin commands; which channel types are echoed is configured with
+    /// [`RawConfig::echo_channel_types`]. Outside of direct messages only
+    /// mentions (`app_mention`) and edits newly mentioning the bot are
+    /// echoed.
+    async fn handle_message(
+        self: &Arc<Self>,
+        installation: &Installation,
+        event: &serde_json::Value,
+        channel_type: Option<ChannelType>,
+        team_id: Option<&str>,
+    ) -> Outcome {
+        let config = self.config();
+        let channel = match event.get("channel").and_then(|v| v.as_str()) {
+            Some(channel) => channel,
+            None => {
+                tracing::warn!("Message event without a channel: {}", event);
+                return Outcome::Ignored;
+            }
+        };
+        let bot_id = event
+            .get("bot_id")
+            .or_else(|| event.get("message").and_then(|m| m.get("bot_id")))
+            .and_then(|v| v.as_str());
+        if let Some(bot_id) = bot_id {
+            if Some(bot_id) == installation.bot_id.as_deref() {
+                tracing::debug!("Ignoring own message");
+                return Outcome::Ignored;
+            }
+            if !config.respond_to_bots {
+                tracing::debug!("Ignoring message of bot {}", bot_id);
+                return Outcome::Ignored;
+            }
+        }
+        let direct = channel_type == Some(ChannelType::Im);
+        // Elsewhere mentions of the bot, admin commands included, also arrive
+        // as `app_mention` and are handled from there, and other messages
+        // aren't meant for the bot. Only edits are looked at, see
+        // `message_to_echo`, which relies on this.
+        let is_edit = event.get("subtype").and_then(|v| v.as_str()) == Some("message_changed");
+        if event.get("type").and_then(|v| v.as_str()) == Some("message") && !direct && !is_edit {
+            tracing::debug!("Ignoring message outside of a direct message");
+            return Outcome::Ignored;
+        }
+        let command = installation.bot_user_id.as_deref().and_then(|bot_user_id| {
+            event
+                .get("text")
+                .and_then(|v| v.as_str())
+                .and_then(|text| parse_admin_command(text, bot_user_id, direct))
+        });
+        let is_admin = event
+            .get("user")
+            .and_then(|v| v.as_str())
+            .is_some_and(|user| config.admin_user_ids.contains(user));
+        // Direct messages of anyone else are echoed (or ignored) as usual, so
+        // a user DMing `status` sees the echo rather than a refusal.
+        let command = command.filter(|_| is_admin || !direct);
+        if direct && !is_admin && config.ignore_non_admin_dms {
+            tracing::debug!("Ignoring direct message of a non-admin");
+            return Outcome::Ignored;
+        }
+        if let Some(command) = command {
+            let locale = match event.get("user").and_then(|v| v.as_str()) {
+                Some(user) if config.messages.is_localized() => {
+                    self.user_locale(installation, user).await
+                }
+                _ => None,
+            };
+            let reply = if is_admin {
+                tracing::info!("Admin command: {:?}", command);
+                self.run_admin_command(command, locale.as_deref())
+            } else {
+                config
+                    .messages
+                    .get("not_authorized", locale.as_deref())
+                    .to_string()
+            };
+            if let Err(e) = installation
+                .slack_client
+                .send_message(channel, &reply)
+                .await
+            {
+                self.report_send_error(installation, channel, e).await;
+                return Outcome::Failed;
+            }
+            return Outcome::Replied;
+        }
+        if self.paused.load(Ordering::SeqCst) {
+            tracing::info!("Paused, ignoring event");
+            return Outcome::Ignored;
+        }
+        if !config.channel_allowed(channel) {
+            tracing::debug!("Ignoring message in channel {}", channel);
+            return Outcome::Ignored;
+        }
+        if let Some(channel_type) = channel_type.filter(|t| !config.echo_channel_types.contains(t))
+        {
+            tracing::debug!("Not echoing in {:?} conversations", channel_type);
+            return Outcome::Ignored;
+        }
+        let message = {
+            let mut echoed = self.echoed.lock().unwrap();
+            match message_to_echo(
+                event,
+                &config,
+                installation.bot_user_id.as_deref(),
+                &mut echoed,
+            ) {
+                Some(message) => message,
+                None => return Outcome::Ignored,
+            }
+        };
+        if self
+            .content_dedup
+            .as_ref()
+            .is_some_and(|dedup| dedup.is_duplicate(channel, message))
+        {
+            tracing::debug!("Ignoring message with duplicate content");
+            return Outcome::Duplicate;
+        }
+        let length = echo_length(
+            message.get("text").and_then(|v| v.as_str()).unwrap_or(""),
+            installation.bot_user_id.as_deref(),
+            config.neutralize_mentions,
+        );
+        if length < config.min_echo_length {
+            tracing::debug!("Ignoring message of {} characters", length);
+            return Outcome::Ignored;
+        }
+        if !in_echo_scope(message, config.echo_scope) {
+            tracing::debug!("Ignoring message outside of {:?}", config.echo_scope);
+            return Outcome::Ignored;
+        }
+        if !self.sampled() {
+            tracing::debug!("Not sampled, ignoring message");
+            return Outcome::Ignored;
+        }
+        // Only recorded once the echo is posted (or buffered to be), so a
+        // dropped or failed echo doesn't silence the thread.
+        let thread = thread_key(channel, message).filter(|_| config.echo_once_per_thread);
+        if thread
+            .as_deref()
+            .is_some_and(|thread| self.replied_in_thread(thread))
+        {
+            tracing::debug!("Already replied in this thread, staying quiet");
+            return Outcome::Ignored;
+        }
+        let cooldown_key = match config.reply_cooldown {
+            Some(cooldown) => {
+                let key = match config.reply_cooldown_key {
+                    CooldownKey::Channel => channel.to_string(),
+                    CooldownKey::Thread => format!(
+                        "{}/{}",
+                        channel,
+                        message
+                            .get("thread_ts")
+                            .or_else(|| message.get("ts"))
+                            .and_then(|v| v.as_str())
+                            .unwrap_or_default()
+                    ),
+                };
+                if !self.reply_cooldowns.try_acquire(&key, cooldown) {
+                    tracing::debug!("{} is cooling down, dropping echo", key);
+                    return Outcome::Ignored;
+                }
+                Some(key)
+            }
+            None => None,
+        };
+        // Only echoes which are posted start a cooldown.
+        let end_cooldown = || {
+            if let Some(key) = &cooldown_key {
+                self.reply_cooldowns.release(key);
+            }
+        };
+        if self
+            .echo_limit
+            .as_ref()
+            .is_some_and(|limit| !limit.try_acquire())
+        {
+            let throttled = self.throttled_echoes.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "Over {} echoes a minute, dropping echo ({} dropped so far)",
+                config.max_echoes_per_minute.unwrap_or_default(),
+                throttled
+            );
+            end_cooldown();
+            return Outcome::Ignored;
+        }
+        self.warm_up().await;
+        let text = echo_text(
+            &installation.slack_client,
+            &config,
+            installation.bot_user_id.as_deref(),
+            message,
+        )
+        .await;
+        let text = match config.coalesce_window {
+            Some(window) => match self
+                .coalescer
+                .push(channel, text, config.coalesce_max_echoes)
+            {
+                Push::Opened(buffer) => {
+                    self.flush_coalesced_later(window, team_id, channel, buffer);
+                    self.record_reply_in_thread(thread.as_deref());
+                    return Outcome::Coalesced;
+                }
+                Push::Buffered => {
+                    self.record_reply_in_thread(thread.as_deref());
+                    return Outcome::Coalesced;
+                }
+                Push::Full(texts) => combine(&texts),
+            },
+            None => text,
+        };
+        match self.post_echo(installation, channel, &text).await {
+            Ok(ts) => {
+                self.record_reply_in_thread(thread.as_deref());
+                Outcome::Echoed { ts }
+            }
+            Err(e) => {
+                end_cooldown();
+                self.report_send_error(installation, channel, e).await;
+                Outcome::Failed
+            }
+        }
+    }
+
+    /// Posts the echoes of a coalescing buffer once its window passed, unless
+    /// the buffer filled up and was posted meanwhile.
+    ///
+    /// This runs outside of the channel's queue so later events of the channel
+    /// can join the buffer in the meantime.
+    fn flush_coalesced_later(
+        self: &Arc<Self>,
+        window: Duration,
+        team_id: Option<&str>,
+        channel: &str,
+        buffer: u64,
+    ) {
+        let app = Arc::clone(self);
+        let team_id = team_id.map(String::from);
+        let channel = channel.to_string();
+        async_std::task::spawn(
+            async move {
+                async_std::task::sleep(window).await;
+                let texts = match app.coalescer.take(&channel, buffer) {
+                    Some(texts) => texts,
+                    None => return,
+                };
+                let installation = match app.installation(team_id.as_deref()) {
+                    Some(installation) => installation,
+                    None => return,
+                };
+                tracing::debug!("Posting {} coalesced echoes", texts.len());
+                if let Err(e) = app
+                    .post_echo(installation, &channel, &combine(&texts))
+                    .await
+                {
+                    app.report_send_error(installation, &channel, e).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Welcomes a new member with a direct message, if enabled.
+    ///
+    /// Members who can't be messaged (e.g. bots, deactivated users or
+    /// workspaces disallowing DMs from apps) are skipped with a log line.
+    async fn handle_team_join(
+        &self,
+        installation: &Installation,
+        event: &serde_json::Value,
+    ) -> Outcome {
+        let config = self.config();
+        let template = match &config.welcome_template {
+            Some(template) => template,
+            None => return Outcome::Ignored,
+        };
+        if self.paused.load(Ordering::SeqCst) {
+            tracing::info!("Paused, not welcoming");
+            return Outcome::Ignored;
+        }
+        let user = event.get("user");
+        if user
+            .and_then(|u| u.get("is_bot"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+        {
+            tracing::debug!("Not welcoming a bot");
+            return Outcome::Ignored;
+        }
+        let user_id = match user.and_then(|u| u.get("id")).and_then(|v| v.as_str()) {
+            Some(user_id) => user_id,
+            None => {
+                tracing::warn!("team_join event without a user id: {}", event);
+                return Outcome::Ignored;
+            }
+        };
+        let text = render_template(template, |name| match name {
+            "user" => Some(format!("<@{}>", user_id)),
+            _ => None,
+        });
+        let result = match installation.slack_client.open_dm(user_id).await {
+            Ok(channel) => {
+                installation
+                    .slack_client
+                    .send_message(&channel, &text)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                tracing::info!("Welcomed {}", user_id);
+                Outcome::Replied
+            }
+            Err(e) if e.is_scope_error() => {
+                tracing::error!(
+                    "Failed to welcome {}, the token lacks a scope: {}",
+                    user_id,
+                    e
+                );
+                Outcome::Failed
+            }
+            Err(e) => {
+                tracing::warn!("Couldn't welcome {}: {}", user_id, e);
+                Outcome::Failed
+            }
+        }
+    }
+
+    /// Joins a newly created public channel, if enabled and the channel is
+    /// allowed.
+    async fn handle_channel_created(
+        &self,
+        installation: &Installation,
+        event: &serde_json::Value,
+    ) -> Outcome {
+        let config = self.config();
+        if !config.auto_join_new_channels {
+            return Outcome::Ignored;
+        }
+        if self.paused.load(Ordering::SeqCst) {
+            tracing::info!("Paused, not joining new channels");
+            return Outcome::Ignored;
+        }
+        let channel = match event
+            .get("channel")
+            .and_then(|c| c.get("id"))
+            .and_then(|v| v.as_str())
+        {
+            Some(channel) => channel,
+            None => {
+                tracing::warn!("channel_created event without a channel id: {}", event);
+                return Outcome::Ignored;
+            }
+        };
+        if !config.channel_allowed(channel) {
+            tracing::debug!("Not joining channel {}", channel);
+            return Outcome::Ignored;
+        }
+        match installation.slack_client.join_channel(channel).await {
+            Ok(()) => {
+                tracing::info!("Joined new channel {}", channel);
+                Outcome::Handled
+            }
+            Err(e) => {
+                // e.g. `is_archived`, or `method_not_supported_for_channel_type`
+                // for private channels.
+                tracing::warn!("Couldn't join new channel {}: {}", channel, e);
+                Outcome::Failed
+            }
+        }
+    }
+
+    /// Records which item was pinned or starred by whom, if enabled.
+    fn handle_engagement(&self, event_type: &str, event: &serde_json::Value) -> Outcome {
+        if !self.config().track_engagement {
+            return Outcome::Ignored;
+        }
+        let str_at = |path: &str| event.pointer(path).and_then(|v| v.as_str());
+        let item_type = str_at("/item/type").unwrap_or("unknown");
+        tracing::info!(
+            "{} by {}: {} {} in {}",
+            event_type,
+            str_at("/user").unwrap_or("-"),
+            item_type,
+            str_at("/item/message/ts")
+                .or_else(|| str_at("/item/file/id"))
+                .unwrap_or("-"),
+            str_at("/item/channel")
+                .or_else(|| str_at("/channel_id"))
+                .unwrap_or("-")
+        );
+        self.engagement
+            .record(&format!("{}:{}", event_type, item_type));
+        Outcome::Handled
+    }
+
+    /// Posts an echo, truncated to the known maximum length.
+    ///
+    /// When Slack rejects it with `msg_too_long`, it is truncated to the
+    /// configured safe length and posted once more, and that length is used
+    /// for later echoes.
+    ///
+    /// Returns the ts of the posted message, after running
+    /// [`App::after_send`].
+    async fn post_echo(
+        &self,
+        installation: &Installation,
+        channel: &str,
+        text: &str,
+    ) -> Result<String, SlackError> {
+        let config = self.config();
+        let post = |text| {
+            let message = echo_message(&config, &installation.slack_client, channel, text);
+            async move { installation.slack_client.post_message(&message).await }
+        };
+        let max_length = self.max_message_length.load(Ordering::Relaxed);
+        let mut posted = truncate_chars(text, max_length);
+        let response = match post(posted).await {
+            Err(e) if e.api_error() == Some("msg_too_long") => {
+                let safe_length = config.safe_message_length;
+                if safe_length >= max_length {
+                    return Err(e);
+                }
+                tracing::warn!(
+                    "Message of {} characters too long, truncating to {}",
+                    text.chars().count(),
+                    safe_length
+                );
+                self.max_message_length
+                    .store(safe_length, Ordering::Relaxed);
+                posted = truncate_chars(text, safe_length);
+                post(posted).await?
+            }
+            result => result?,
+        };
+        if let Err(e) = self
+            .after_send
+            .after_send(&response.channel, &response.ts, posted)
+            .await
+        {
+            tracing::warn!("after_send hook failed for {}: {}", response.ts, e);
+        }
+        Ok(response.ts)
+    }
+
+    /// Whether to reply to a message, with a probability of
+    /// [`RawConfig::response_sample_rate`].
+    fn sampled(&self) -> bool {
+        let config = self.config();
+        let rate = config.response_sample_rate;
+        rate >= 1.0 || self.sampler.lock().unwrap().gen::<f64>() < rate
+    }
+
+    /// Whether the bot already replied in the thread `thread`, see
+    /// [`thread_key`].
+    fn replied_in_thread(&self, thread: &str) -> bool {
+        self.replied_threads.lock().unwrap().contains(thread)
+    }
+
+    /// Records that the bot replied in the thread `thread`.
+    fn record_reply_in_thread(&self, thread: Option<&str>) {
+        if let Some(thread) = thread {
+            self.replied_threads.lock().unwrap().insert(thread);
+        }
+    }
+
+    /// Logs a failed post to `channel`. Scope errors are called out, and admins
+    /// are told about them with an ephemeral message if enabled.
+    async fn report_send_error(
+        &self,
+        installation: &Installation,
+        channel: &str,
+        error: SlackError,
+    ) {
+        let config = self.config();
+        if !error.is_scope_error() {
+            tracing::error!("Failed to send message to {}: {}", channel, error);
+            return;
+        }
+        tracing::error!(
+            "Failed to send message to {}, the token lacks a scope: {}",
+            channel,
+            error
+        );
+        if !config.notify_admins_on_scope_error {
+            return;
+        }
+        // The notice is posted with `chat.postEphemeral`, which needs the same
+        // scope.
+        if error.needed_scope() == Some("chat:write") {
+            tracing::warn!("Not notifying admins, the token can't post without `chat:write`");
+            return;
+        }
+        let text = render_template(config.messages.get("scope_error_notice", None), |name| {
+            (name == "error").then(|| error.to_string())
+        });
+        for admin in &config.admin_user_ids {
+            if let Err(e) = installation
+                .slack_client
+                .send_ephemeral(channel, admin, &text)
+                .await
+            {
+                tracing::warn!("Failed to notify admin {}: {}", admin, e);
+            }
+        }
+    }
+
+    /// Locale of `user`, if Slack knows it.
+    async fn user_locale(&self, installation: &Installation, user: &str) -> Option<String> {
+        match installation.slack_client.users_info(user).await {
+            Ok(res) => res.user.locale,
+            Err(e) => {
+                tracing::warn!("users.info failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Runs an admin command and returns the reply in `locale`.
+    fn run_admin_command(&self, command: AdminCommand, locale: Option<&str>) -> String {
+        let config = self.config();
+        match command {
+            AdminCommand::Pause => {
+                self.paused.store(true, Ordering::SeqCst);
+                config.messages.get("paused", locale).to_string()
+            }
+            AdminCommand::Resume => {
+                self.paused.store(false, Ordering::SeqCst);
+                config.messages.get("resumed", locale).to_string()
+            }
+            AdminCommand::Status => format!(
+                "Status: {}\nEvents: {}\nEngagement: {}\nFrame sizes: {}\nThrottled echoes: {}",
+                if self.paused.load(Ordering::SeqCst) {
+                    "paused"
+                } else {
+                    "running"
+                },
+                self.event_types.summary(),
+                self.engagement.summary(),
+                self.frame_sizes.summary(),
+                self.throttled_echoes.load(Ordering::Relaxed)
+            ),
+            AdminCommand::Reconnects => {
+                format!("```{}```", self.reconnects.lock().unwrap().to_json())
+            }
+            AdminCommand::Reload => match self.reload_config() {
+                Ok(changed) => render_template(config.messages.get("reloaded", locale), |name| {
+                    (name == "changed").then(|| {
+                        if changed.is_empty() {
+                            "nothing".to_string()
+                        } else {
+                            changed.join(", ")
+                        }
+                    })
+                }),
+                Err(e) => render_template(config.messages.get("reload_failed", locale), |name| {
+                    (name == "error").then(|| e.clone())
+                }),
+            },
+            AdminCommand::Reconnect => {
+                // A request already pending will do.
+                let _ = self.reconnect_requests.0.try_send(());
+                config.messages.get("reconnecting", locale).to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::HookFuture;
+    use crate::interactive::ViewClosed;
+    use crate::test_server::{Response, TestServer};
+    use serde_json::json;
+
+    fn app(vars: &[(&str, &str)]) -> Arc<App> {
+        App::for_test(RawConfig::for_test(vars).unwrap())
+    }
+
+    fn app_with_api(vars: &[(&str, &str)], server: &TestServer) -> Arc<App> {
+        App::for_test_with_api(RawConfig::for_test(vars).unwrap(), &server.api_base())
+    }
+
+    /// A Web API posting every message as `1234.5678`.
+    fn posting_server() -> TestServer {
+        TestServer::start(|_| {
+            Response::json(json!({"ok": true, "channel": "D1", "ts": "1234.5678"}))
+        })
+    }
+
+    fn echoed() -> Outcome {
+        Outcome::Echoed {
+            ts: "1234.5678".to_string(),
+        }
+    }
+
+    fn bot_dm(bot_id: &str) -> serde_json::Value {
+        json!({
+            "type": "message",
+            "subtype": "bot_message",
+            "channel": "D1",
+            "channel_type": "im",
+            "bot_id": bot_id,
+            "text": "beep",
+            "ts": "1.0",
+        })
+    }
+
+    fn dm(text: &str, ts: &str) -> serde_json::Value {
+        json!({
+            "type": "message",
+            "channel": "D1",
+            "channel_type": "im",
+            "user": "U1",
+            "text": text,
+            "ts": ts,
+            "thread_ts": "1.0",
+        })
+    }
+
+    /// A Web API rejecting `chat.postMessage` for lack of `needed`.
+    fn missing_scope_server(needed: &'static str) -> TestServer {
+        TestServer::start(move |request| match request.method() {
+            "chat.postMessage" => Response::json(json!({
+                "ok": false,
+                "error": "missing_scope",
+                "needed": needed,
+                "provided": "im:history",
+            })),
+            _ => Response::json(json!({"ok": true})),
+        })
+    }
+
+    #[async_std::test]
+    async fn admins_are_told_about_scope_errors() {
+        let server = missing_scope_server("chat:write.public");
+        let app = app_with_api(
+            &[
+                ("SLACK_ADMIN_USER_IDS", "UADMIN"),
+                ("SLACK_NOTIFY_ADMINS_ON_SCOPE_ERROR", "true"),
+            ],
+            &server,
+        );
+        let outcome = app.handle_event(&dm("hi", "1.1"), None).await;
+        assert_eq!(outcome, Outcome::Failed);
+        assert_eq!(server.methods(), ["chat.postMessage", "chat.postEphemeral"]);
+        let notice = server.requests()[1].json();
+        assert_eq!(notice["channel"], "D1");
+        assert_eq!(notice["user"], "UADMIN");
+        assert_eq!(
+            notice["text"],
+            "I couldn't reply in this channel: missing_scope \
+             (grant the `chat:write.public` scope; provided: im:history)"
+        );
+    }
+
+    #[async_std::test]
+    async fn admins_are_not_told_when_posting_lacks_chat_write() {
+        let server = missing_scope_server("chat:write");
+        let app = app_with_api(
+            &[
+                ("SLACK_ADMIN_USER_IDS", "UADMIN"),
+                ("SLACK_NOTIFY_ADMINS_ON_SCOPE_ERROR", "true"),
+            ],
+            &server,
+        );
+        let outcome = app.handle_event(&dm("hi", "1.1"), None).await;
+        assert_eq!(outcome, Outcome::Failed);
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+    }
+
+    #[async_std::test]
+    async fn failed_echo_does_not_use_up_the_thread() {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let server = TestServer::start(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => Response::json(json!({"ok": false, "error": "internal_error"})),
+            _ => Response::json(json!({"ok": true, "channel": "D1", "ts": "1234.5678"})),
+        });
+        let app = app_with_api(&[("SLACK_ECHO_ONCE_PER_THREAD", "true")], &server);
+        app.handle_event(&dm("one", "1.1"), None).await;
+        app.handle_event(&dm("two", "1.2"), None).await;
+        app.handle_event(&dm("three", "1.3"), None).await;
+        assert_eq!(server.methods().len(), 2);
+        assert_eq!(server.requests()[1].json()["text"], "You said: ```two```");
+    }
+
+    #[async_std::test]
+    async fn too_long_echoes_are_truncated_from_then_on() {
+        let server = TestServer::start(|request| {
+            let text = request.json()["text"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            match text.chars().count() {
+                0..=5 => Response::json(json!({"ok": true, "channel": "D1", "ts": "1234.5678"})),
+                _ => Response::json(json!({"ok": false, "error": "msg_too_long"})),
+            }
+        });
+        let app = app_with_api(
+            &[
+                ("SLACK_ECHO_FORMAT", "plain"),
+                ("SLACK_SAFE_MESSAGE_LENGTH", "5"),
+            ],
+            &server,
+        );
+        app.handle_event(&dm("héllo wörld", "1.1"), None).await;
+        app.handle_event(&dm("abcdefgh", "1.2"), None).await;
+        let texts: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|request| request.json()["text"].clone())
+            .collect();
+        assert_eq!(
+            texts,
+            [json!("héllo wörld"), json!("héllo"), json!("abcde")]
+        );
+    }
+
+    #[async_std::test]
+    async fn messages_below_the_minimum_length_are_not_echoed() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_MIN_ECHO_LENGTH", "3")], &server);
+        app.handle_event(&dm("<@UBOT> ab", "1.1"), None).await;
+        assert!(server.methods().is_empty());
+        app.handle_event(&dm("<@UBOT> abc", "1.2"), None).await;
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+    }
+
+    #[async_std::test]
+    async fn cooldowns_only_start_once_an_echo_is_posted() {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let server = TestServer::start(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => Response::json(json!({"ok": false, "error": "internal_error"})),
+            _ => Response::json(json!({"ok": true, "channel": "D1", "ts": "1234.5678"})),
+        });
+        let app = app_with_api(
+            &[
+                ("SLACK_REPLY_COOLDOWN_MS", "60000"),
+                ("SLACK_REPLY_COOLDOWN_KEY", "thread"),
+            ],
+            &server,
+        );
+        assert_eq!(
+            app.handle_event(&dm("one", "1.1"), None).await,
+            Outcome::Failed
+        );
+        assert_eq!(app.handle_event(&dm("two", "1.2"), None).await, echoed());
+        assert_eq!(
+            app.handle_event(&dm("three", "1.3"), None).await,
+            Outcome::Ignored
+        );
+        // Other threads have their own cooldown.
+        let other_thread = json!({
+            "type": "message",
+            "channel": "D1",
+            "channel_type": "im",
+            "user": "U1",
+            "text": "four",
+            "ts": "2.0",
+        });
+        assert_eq!(app.handle_event(&other_thread, None).await, echoed());
+        assert_eq!(server.methods().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn echoes_over_the_global_cap_are_dropped_and_counted() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_MAX_ECHOES_PER_MINUTE", "2")], &server);
+        for i in 0..3 {
+            app.handle_event(&dm("hi", &format!("1.{}", i)), None).await;
+        }
+        assert_eq!(app.throttled_echoes.load(Ordering::Relaxed), 1);
+        assert_eq!(server.methods().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn new_members_are_not_welcomed_while_paused() {
+        let server = TestServer::start(|request| match request.method() {
+            "conversations.open" => Response::json(json!({"ok": true, "channel": {"id": "D9"}})),
+            _ => Response::json(json!({"ok": true, "channel": "D9", "ts": "1234.5678"})),
+        });
+        let app = app_with_api(&[("SLACK_WELCOME_TEMPLATE", "Welcome {user}!")], &server);
+        let team_join = json!({"type": "team_join", "user": {"id": "U2"}});
+        app.paused.store(true, Ordering::SeqCst);
+        assert_eq!(app.handle_event(&team_join, None).await, Outcome::Ignored);
+        assert!(server.methods().is_empty());
+
+        app.paused.store(false, Ordering::SeqCst);
+        assert_eq!(app.handle_event(&team_join, None).await, Outcome::Replied);
+        assert_eq!(server.methods(), ["conversations.open", "chat.postMessage"]);
+        assert_eq!(server.requests()[1].json()["text"], "Welcome <@U2>!");
+    }
+
+    #[async_std::test]
+    async fn new_channels_are_joined_unless_paused_or_filtered() {
+        let server = TestServer::start(|_| Response::json(json!({"ok": true})));
+        let app = app_with_api(
+            &[
+                ("SLACK_AUTO_JOIN_NEW_CHANNELS", "true"),
+                ("SLACK_DENIED_CHANNELS", "C2"),
+            ],
+            &server,
+        );
+        let created = |id| json!({"type": "channel_created", "channel": {"id": id}});
+        app.paused.store(true, Ordering::SeqCst);
+        assert_eq!(
+            app.handle_event(&created("C1"), None).await,
+            Outcome::Ignored
+        );
+        assert!(server.methods().is_empty());
+
+        app.paused.store(false, Ordering::SeqCst);
+        assert_eq!(
+            app.handle_event(&created("C2"), None).await,
+            Outcome::Ignored
+        );
+        assert_eq!(
+            app.handle_event(&created("C1"), None).await,
+            Outcome::Handled
+        );
+        assert_eq!(server.methods(), ["conversations.join"]);
+        assert_eq!(server.requests()[0].json()["channel"], "C1");
+    }
+
+    fn message_in(channel_type: &str, text: &str) -> serde_json::Value {
+        json!({
+            "type": "message",
+            "channel": "C1",
+            "channel_type": channel_type,
+            "user": "UADMIN",
+            "text": text,
+            "ts": "1.0",
+        })
+    }
+
+    #[async_std::test]
+    async fn only_direct_messages_are_echoed_from_message_events() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_ADMIN_USER_IDS", "UADMIN")], &server);
+        for channel_type in ["channel", "group", "mpim"] {
+            for text in ["hi", "<@UBOT> hi", "<@UBOT> pause"] {
+                let message = message_in(channel_type, text);
+                assert_eq!(app.handle_event(&message, None).await, Outcome::Ignored);
+            }
+        }
+        assert!(!app.paused.load(Ordering::SeqCst));
+        assert!(server.methods().is_empty());
+        let message = message_in("im", "hi");
+        assert_eq!(app.handle_event(&message, None).await, echoed());
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+    }
+
+    #[async_std::test]
+    async fn mentions_are_echoed_only_if_their_channel_type_is() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_ECHO_CHANNEL_TYPES", "im")], &server);
+        for channel in ["C1", "G1"] {
+            let event = json!({
+                "type": "app_mention",
+                "channel": channel,
+                "user": "U1",
+                "text": "<@UBOT> hi",
+                "ts": "1.0",
+            });
+            assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        }
+        assert!(server.methods().is_empty());
+    }
+
+    #[async_std::test]
+    async fn direct_messages_are_echoed_only_if_their_channel_type_is() {
+        let server = posting_server();
+        let app = app_with_api(
+            &[
+                ("SLACK_ECHO_CHANNEL_TYPES", "channel,group"),
+                ("SLACK_ADMIN_USER_IDS", "UADMIN"),
+            ],
+            &server,
+        );
+        let message = message_in("im", "hi");
+        assert_eq!(app.handle_event(&message, None).await, Outcome::Ignored);
+        assert!(server.methods().is_empty());
+        // Admin commands work regardless.
+        let message = message_in("im", "pause");
+        assert_eq!(app.handle_event(&message, None).await, Outcome::Replied);
+        assert!(app.paused.load(Ordering::SeqCst));
+        // Mentions in channels are echoed from `app_mention`.
+        app.paused.store(false, Ordering::SeqCst);
+        let mention = json!({"type": "app_mention", "channel": "C1", "user": "U1", "text": "<@UBOT> hi", "ts": "2.0"});
+        assert_eq!(app.handle_event(&mention, None).await, echoed());
+    }
+
+    #[async_std::test]
+    async fn echoes_within_the_window_are_posted_together() {
+        let server = posting_server();
+        let app = app_with_api(
+            &[
+                ("SLACK_ECHO_FORMAT", "plain"),
+                ("SLACK_COALESCE_WINDOW_MS", "50"),
+            ],
+            &server,
+        );
+        assert_eq!(
+            app.handle_event(&dm("a", "1.1"), None).await,
+            Outcome::Coalesced
+        );
+        assert_eq!(
+            app.handle_event(&dm("b", "1.2"), None).await,
+            Outcome::Coalesced
+        );
+        assert!(server.methods().is_empty());
+        async_std::task::sleep(Duration::from_millis(300)).await;
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["text"], "a\nb");
+    }
+
+    /// Records what it is called with, then fails if `fail` is set.
+    struct RecordingHook {
+        calls: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl AfterSend for RecordingHook {
+        fn after_send<'a>(
+            &'a self,
+            channel: &'a str,
+            ts: &'a str,
+            text: &'a str,
+        ) -> HookFuture<'a> {
+            Box::pin(async move {
+                self.calls
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {} {}", channel, ts, text));
+                if self.fail {
+                    return Err("unreachable".to_string());
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[async_std::test]
+    async fn after_send_hook_sees_each_posted_echo() {
+        let server = posting_server();
+        for fail in [false, true] {
+            let config = RawConfig::for_test(&[("SLACK_ECHO_FORMAT", "plain")]).unwrap();
+            let installation = Installation::for_test(&config, &server.api_base());
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let app = Arc::new(
+                App::with_installations(config, installation, HashMap::new()).with_after_send(
+                    RecordingHook {
+                        calls: Arc::clone(&calls),
+                        fail,
+                    },
+                ),
+            );
+            // A failing hook doesn't fail the echo.
+            assert_eq!(app.handle_event(&dm("hi", "1.1"), None).await, echoed());
+            assert_eq!(*calls.lock().unwrap(), ["D1 1234.5678 hi"]);
+        }
+    }
+
+    /// Records the closed views' ids, then fails if `fail` is set.
+    struct RecordingViewClosed {
+        closed: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl ViewClosedHandler for RecordingViewClosed {
+        fn view_closed<'a>(&'a self, closed: &'a ViewClosed) -> HookFuture<'a> {
+            Box::pin(async move {
+                self.closed.lock().unwrap().push(closed.view.id.clone());
+                if self.fail {
+                    return Err("unreachable".to_string());
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[async_std::test]
+    async fn view_closed_handler_sees_dismissed_modals() {
+        let payload: InteractivePayload = serde_json::from_value(json!({
+            "type": "view_closed",
+            "user": {"id": "U1"},
+            "view": {"id": "V1"},
+        }))
+        .unwrap();
+        for (fail, outcome) in [(false, Outcome::Handled), (true, Outcome::Failed)] {
+            let config = RawConfig::for_test(&[]).unwrap();
+            let installation = Installation::for_test(&config, SLACK_API_BASE);
+            let closed = Arc::new(Mutex::new(Vec::new()));
+            let app = App::with_installations(config, installation, HashMap::new())
+                .with_view_closed_handler(RecordingViewClosed {
+                    closed: Arc::clone(&closed),
+                    fail,
+                });
+            assert_eq!(app.handle_interactive(&payload).await, outcome);
+            assert_eq!(*closed.lock().unwrap(), ["V1"]);
+            assert_eq!(
+                app.handle_interactive(&InteractivePayload::Other).await,
+                Outcome::Ignored
+            );
+            assert_eq!(closed.lock().unwrap().len(), 1);
+        }
+    }
+
+    fn mention(text: &str, ts: &str) -> serde_json::Value {
+        json!({
+            "type": "app_mention",
+            "channel": "C1",
+            "user": "U1",
+            "text": text,
+            "ts": ts,
+        })
+    }
+
+    #[async_std::test]
+    async fn nothing_is_echoed_at_a_sample_rate_of_zero() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_RESPONSE_SAMPLE_RATE", "0")], &server);
+        for i in 0..10 {
+            let event = mention("<@UBOT> hi", &format!("1.{}", i));
+            assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        }
+        assert!(server.methods().is_empty());
+    }
+
+    #[async_std::test]
+    async fn every_mention_is_echoed_at_a_sample_rate_of_one() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_RESPONSE_SAMPLE_RATE", "1")], &server);
+        for i in 0..10 {
+            let event = mention("<@UBOT> hi", &format!("1.{}", i));
+            assert_eq!(app.handle_event(&event, None).await, echoed());
+        }
+        assert_eq!(server.methods(), ["chat.postMessage"; 10]);
+    }
+
+    #[async_std::test]
+    async fn only_admins_run_commands() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_ADMIN_USER_IDS", "UADMIN")], &server);
+        let mention = |user| {
+            json!({
+                "type": "app_mention",
+                "channel": "C1",
+                "user": user,
+                "text": "<@UBOT> pause",
+                "ts": "1.0",
+            })
+        };
+        assert_eq!(
+            app.handle_event(&mention("U1"), None).await,
+            Outcome::Replied
+        );
+        assert!(!app.paused.load(Ordering::SeqCst));
+        assert_eq!(
+            app.handle_event(&mention("UADMIN"), None).await,
+            Outcome::Replied
+        );
+        assert!(app.paused.load(Ordering::SeqCst));
+        let texts: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|request| request.json()["text"].clone())
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                json!("You are not authorized to run this command."),
+                json!("Paused. Use the `resume` command to continue.")
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn commands_of_non_admins_in_direct_messages_are_echoed() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_ADMIN_USER_IDS", "UADMIN")], &server);
+        assert_eq!(app.handle_event(&dm("pause", "1.1"), None).await, echoed());
+        assert!(!app.paused.load(Ordering::SeqCst));
+        assert_eq!(server.requests()[0].json()["text"], "You said: ```pause```");
+    }
+
+    #[test]
+    fn reloading_swaps_in_the_new_settings() {
+        let app = app(&[("SLACK_ECHO_TEMPLATE", "old {text}")]);
+        let before = app.config();
+        let changed = app.swap_config(
+            RawConfig::for_test(&[
+                ("SLACK_ECHO_TEMPLATE", "new {text}"),
+                ("SLACK_RESPOND_TO_BOTS", "true"),
+            ])
+            .unwrap(),
+        );
+        assert_eq!(changed, ["echo_template", "respond_to_bots"]);
+        assert_eq!(app.config().echo_template, "new {text}");
+        assert!(app.config().respond_to_bots);
+        // Handlers which already started keep their config.
+        assert_eq!(before.echo_template, "old {text}");
+    }
+
+    #[test]
+    fn events_go_to_the_first_authorized_team_with_an_installation() {
+        let config = RawConfig::for_test(&[]).unwrap();
+        let teams = ["T2", "T3"]
+            .map(|team| {
+                (
+                    team.to_string(),
+                    Installation::for_test(&config, SLACK_API_BASE),
+                )
+            })
+            .into();
+        let installation = Installation::for_test(&config, SLACK_API_BASE);
+        let app = App::with_installations(config, installation, teams);
+        let payload = json!({
+            "team_id": "T3",
+            "authorizations": [{"team_id": "T1"}, {"team_id": "T2"}],
+        });
+        assert_eq!(app.authorized_team(&payload).as_deref(), Some("T2"));
+        let payload = json!({"team_id": "T3", "authorizations": [{"team_id": "T1"}]});
+        assert_eq!(app.authorized_team(&payload).as_deref(), Some("T3"));
+        let payload = json!({"authorizations": [{"team_id": "T1"}, {"team_id": "T4"}]});
+        assert_eq!(app.authorized_team(&payload).as_deref(), Some("T1"));
+        assert_eq!(app.authorized_team(&json!({})), None);
+    }
+
+    #[test]
+    fn replies_wait_until_the_end_of_the_warmup() {
+        let without_warmup = app(&[]);
+        without_warmup.start_warmup();
+        assert_eq!(without_warmup.warmup_remaining(Instant::now()), None);
+
+        let app = app(&[("SLACK_WARMUP_MS", "1000")]);
+        assert_eq!(app.warmup_remaining(Instant::now()), None);
+        let before = Instant::now();
+        app.start_warmup();
+        let warm_until = app.warm_until.lock().unwrap().unwrap();
+        assert!(warm_until >= before + Duration::from_millis(1000));
+        let just_before = warm_until - Duration::from_millis(1);
+        assert_eq!(
+            app.warmup_remaining(just_before),
+            Some((Duration::from_millis(1), Duration::from_millis(1000)))
+        );
+        assert_eq!(app.warmup_remaining(warm_until), None);
+    }
+
+    #[test]
+    fn retried_deliveries_are_processed_once() {
+        let app = app(&[]);
+        assert!(app.first_delivery(Some("Ev1"), 0, None));
+        assert!(!app.first_delivery(Some("Ev1"), 1, Some("timeout")));
+        // A retry is processed if the first delivery never made it.
+        assert!(app.first_delivery(Some("Ev2"), 1, Some("timeout")));
+        assert!(!app.first_delivery(Some("Ev2"), 2, Some("timeout")));
+        // Events without an id can't be told apart.
+        assert!(app.first_delivery(None, 0, None));
+        assert!(app.first_delivery(None, 1, None));
+    }
+
+    #[async_std::test]
+    async fn same_content_of_another_delivery_is_echoed_once() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_CONTENT_DEDUP_WINDOW_SECS", "60")], &server);
+        app.handle_event(&dm("hi", "1.1"), None).await;
+        // Resent under another ts, as by an overlapping subscription.
+        app.handle_event(&dm("hi", "1.2"), None).await;
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+    }
+
+    #[async_std::test]
+    async fn own_messages_are_ignored_even_when_responding_to_bots() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_RESPOND_TO_BOTS", "true")], &server);
+        app.handle_event(&bot_dm("BBOT"), None).await;
+        assert!(server.methods().is_empty());
+    }
+
+    #[async_std::test]
+    async fn other_bots_are_echoed_only_when_enabled() {
+        let server = posting_server();
+        let app = app_with_api(&[], &server);
+        app.handle_event(&bot_dm("B9"), None).await;
+        assert!(server.methods().is_empty());
+
+        let app = app_with_api(&[("SLACK_RESPOND_TO_BOTS", "true")], &server);
+        app.handle_event(&bot_dm("B9"), None).await;
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+        assert_eq!(server.requests()[0].json()["text"], "You said: ```beep```");
+    }
+
+    #[async_std::test]
+    async fn plain_echoes_disable_mrkdwn_and_decoration() {
+        let app = app(&[("SLACK_ECHO_FORMAT", "plain")]);
+        let config = app.config();
+        let message = json!({"user": "U1", "text": "*not bold*", "ts": "1.0"});
+        let client = &app.installation.slack_client;
+        let text = echo_text(client, &config, Some("UBOT"), &message).await;
+        let body = serde_json::to_value(echo_message(&config, client, "C1", &text)).unwrap();
+        assert_eq!(body["text"], "*not bold*");
+        assert_eq!(body["mrkdwn"], false);
+    }
+
+    #[async_std::test]
+    async fn code_echoes_keep_mrkdwn() {
+        let app = app(&[]);
+        let config = app.config();
+        let message = json!({"user": "U1", "text": "hi", "ts": "1.0"});
+        let client = &app.installation.slack_client;
+        let text = echo_text(client, &config, Some("UBOT"), &message).await;
+        let body = serde_json::to_value(echo_message(&config, client, "C1", &text)).unwrap();
+        assert_eq!(body["text"], "You said: ```hi```");
+        assert_eq!(body["mrkdwn"], true);
+    }
+
+    fn pin_and_star() -> [serde_json::Value; 2] {
+        [
+            json!({
+                "type": "pin_added",
+                "user": "U1",
+                "channel_id": "C1",
+                "item": {"type": "message", "channel": "C1", "message": {"ts": "1.0"}},
+            }),
+            json!({
+                "type": "star_added",
+                "user": "U1",
+                "item": {"type": "file", "file": {"id": "F1"}},
+            }),
+        ]
+    }
+
+    #[async_std::test]
+    async fn pins_and_stars_are_counted_when_tracked() {
+        let server = posting_server();
+        let app = app_with_api(&[("SLACK_TRACK_ENGAGEMENT", "true")], &server);
+        for event in pin_and_star() {
+            assert_eq!(app.handle_event(&event, None).await, Outcome::Handled);
+        }
+        assert_eq!(
+            app.engagement.summary(),
+            "pin_added:message=1, star_added:file=1"
+        );
+        assert!(server.methods().is_empty());
+    }
+
+    #[async_std::test]
+    async fn pins_and_stars_are_ignored_unless_tracked() {
+        let server = posting_server();
+        let app = app_with_api(&[], &server);
+        for event in pin_and_star() {
+            assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        }
+        assert_eq!(app.engagement.summary(), "none");
+        assert!(server.methods().is_empty());
+    }
+
+    #[async_std::test]
+    async fn unknown_inner_event_is_ignored_and_counted() {
+        let app = app(&[]);
+        let event = json!({"type": "reaction_added", "user": "U1"});
+        assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        let event = json!({"no": "type"});
+        assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        let counts = app.event_types.summary();
+        assert!(counts.contains("reaction_added=1"), "{}", counts);
+        assert!(counts.contains("unknown=1"), "{}", counts);
+    }
+
+    #[async_std::test]
+    async fn known_inner_event_is_dispatched_and_counted() {
+        let app = app(&[("SLACK_TRACK_ENGAGEMENT", "true")]);
+        // A plain channel message reaches the message handler, which leaves it
+        // to `app_mention`.
+        let event = json!({
+            "type": "message",
+            "channel_type": "channel",
+            "channel": "C1",
+            "user": "U1",
+            "text": "hi",
+            "ts": "1.0",
+        });
+        assert_eq!(app.handle_event(&event, None).await, Outcome::Ignored);
+        let event = json!({
+            "type": "pin_added",
+            "user": "U1",
+            "item": {"type": "message", "channel": "C1", "message": {"ts": "1.0"}},
+        });
+        assert_eq!(app.handle_event(&event, None).await, Outcome::Handled);
+        let counts = app.event_types.summary();
+        assert_eq!(counts, "message.channels=1, pin_added=1");
+        assert_eq!(app.engagement.summary(), "pin_added:message=1");
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::{HashSet, VecDeque};
+
+/// A set which forgets its oldest entries once `capacity` is exceeded.
+pub struct BoundedSet {
+    capacity: usize,
+    order: VecDeque<String>,
+    items: HashSet<String>,
+}
+
+impl BoundedSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            items: HashSet::with_capacity(capacity),
+        }
+    }
+
+    pub fn contains(&self, value: &str) -> bool {
+        self.items.contains(value)
+    }
+
+    /// Returns `true` if the value was not present yet.
+    pub fn insert(&mut self, value: &str) -> bool {
+        if self.items.contains(value) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        self.order.push_back(value.to_string());
+        self.items.insert(value.to_string());
+        true
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Buffers echoes per key (a channel) so that echoes arriving within a short
+/// window are posted as a single message.
+///
+/// The first echo of a key opens a buffer and whoever pushed it is expected
+/// to flush it with [`Coalescer::take`] once the window passed. Buffers are
+/// numbered so that a late flush doesn't take a newer buffer, opened after the
+/// previous one filled up and was flushed early.
+#[derive(Default)]
+pub struct Coalescer {
+    pending: Mutex<HashMap<String, (u64, Vec<String>)>>,
+    next_buffer: AtomicU64,
+}
+
+pub enum Push {
+    /// A new buffer was opened; flush it after the window.
+    Opened(u64),
+    /// Added to an open buffer.
+    Buffered,
+    /// The buffer reached its maximum size and must be posted now.
+    Full(Vec<String>),
+}
+
+impl Coalescer {
+    pub fn push(&self, key: &str, text: String, max_echoes: usize) -> Push {
+        let mut pending = self.pending.lock().unwrap();
+        let push = match pending.get_mut(key) {
+            Some((_, texts)) => {
+                texts.push(text);
+                if texts.len() < max_echoes {
+                    return Push::Buffered;
+                }
+                Push::Full(std::mem::take(texts))
+            }
+            None if max_echoes <= 1 => return Push::Full(vec![text]),
+            None => {
+                let buffer = self.next_buffer.fetch_add(1, Ordering::Relaxed);
+                pending.insert(key.to_string(), (buffer, vec![text]));
+                return Push::Opened(buffer);
+            }
+        };
+        pending.remove(key);
+        push
+    }
+
+    /// Echoes of buffer `buffer` of `key`, unless it was already flushed.
+    pub fn take(&self, key: &str, buffer: u64) -> Option<Vec<String>> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(key) {
+            Some((open, _)) if *open == buffer => pending.remove(key).map(|(_, texts)| texts),
+            _ => None,
+        }
+    }
+}
+
+/// One message listing each of `texts`, one per line.
+pub fn combine(texts: &[String]) -> String {
+    texts.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_echoes_until_taken() {
+        let coalescer = Coalescer::default();
+        let buffer = match coalescer.push("C1", "a".to_string(), 10) {
+            Push::Opened(buffer) => buffer,
+            _ => panic!("expected a new buffer"),
+        };
+        assert!(matches!(
+            coalescer.push("C1", "b".to_string(), 10),
+            Push::Buffered
+        ));
+        assert!(matches!(
+            coalescer.push("C2", "c".to_string(), 10),
+            Push::Opened(_)
+        ));
+        assert_eq!(
+            coalescer.take("C1", buffer),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(coalescer.take("C1", buffer), None);
+    }
+
+    #[test]
+    fn full_buffers_are_posted_early_and_not_taken_again() {
+        let coalescer = Coalescer::default();
+        let buffer = match coalescer.push("C1", "a".to_string(), 2) {
+            Push::Opened(buffer) => buffer,
+            _ => panic!("expected a new buffer"),
+        };
+        match coalescer.push("C1", "b".to_string(), 2) {
+            Push::Full(texts) => assert_eq!(combine(&texts), "a\nb"),
+            _ => panic!("expected a full buffer"),
+        }
+        // The late flush of the first buffer leaves the next one alone.
+        assert!(matches!(
+            coalescer.push("C1", "c".to_string(), 2),
+            Push::Opened(_)
+        ));
+        assert_eq!(coalescer.take("C1", buffer), None);
+    }
+
+    #[test]
+    fn nothing_is_buffered_with_a_maximum_of_one() {
+        let coalescer = Coalescer::default();
+        assert!(
+            matches!(coalescer.push("C1", "a".to_string(), 1), Push::Full(texts) if texts == ["a"])
+        );
+    }
+}
@@ -0,0 +1,658 @@
+use crate::echo::{
+    DEFAULT_ECHO_TEMPLATE, DEFAULT_MENTION_ECHO_TEMPLATE, DEFAULT_PLAIN_ECHO_TEMPLATE,
+    DEFAULT_PLAIN_MENTION_ECHO_TEMPLATE,
+};
+use crate::messages::Messages;
+use crate::slack::{normalize_emoji, ParseMode};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How echoes are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoFormat {
+    /// mrkdwn, with the text in a code block by default.
+    Code,
+    /// Plain text: mrkdwn disabled and no decoration by default.
+    Plain,
+}
+
+impl std::str::FromStr for EchoFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "code" => Ok(Self::Code),
+            "plain" => Ok(Self::Plain),
+            _ => Err(format!("unknown echo format: {}", s)),
+        }
+    }
+}
+
+/// Which messages are echoed by their place in threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoScope {
+    All,
+    /// Top level messages, including thread parents.
+    TopLevel,
+    /// Replies in threads.
+    Threads,
+}
+
+impl std::str::FromStr for EchoScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "top_level" => Ok(Self::TopLevel),
+            "threads" => Ok(Self::Threads),
+            _ => Err(format!("unknown echo scope: {}", s)),
+        }
+    }
+}
+
+/// What the reply cooldown applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CooldownKey {
+    Channel,
+    /// Each thread, and each top level message, separately.
+    Thread,
+}
+
+impl std::str::FromStr for CooldownKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(Self::Channel),
+            "thread" => Ok(Self::Thread),
+            _ => Err(format!("unknown cooldown key: {}", s)),
+        }
+    }
+}
+
+/// Kind of conversation a message was posted in, from its `channel_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelType {
+    /// Public channel.
+    Channel,
+    /// Private channel.
+    Group,
+    /// Direct message.
+    Im,
+    /// Multi-person direct message.
+    Mpim,
+}
+
+impl ChannelType {
+    /// Channel type of a `message` event, if it has one. `app_mention` events
+    /// don't, see [`Self::of_channel_id`].
+    pub fn of_event(event: &serde_json::Value) -> Option<Self> {
+        event.get("channel_type")?.as_str()?.parse().ok()
+    }
+
+    /// Channel type told by the prefix of a channel id, for events without a
+    /// `channel_type`. Group direct messages have `G` ids just like private
+    /// channels, and are taken for ones.
+    pub fn of_channel_id(channel: &str) -> Option<Self> {
+        match channel.chars().next()? {
+            'C' => Some(Self::Channel),
+            'G' => Some(Self::Group),
+            'D' => Some(Self::Im),
+            _ => None,
+        }
+    }
+
+    /// The compound event type Slack subscribes to for this channel type,
+    /// e.g. `message.im`.
+    pub fn message_event_type(self) -> &'static str {
+        match self {
+            Self::Channel => "message.channels",
+            Self::Group => "message.groups",
+            Self::Im => "message.im",
+            Self::Mpim => "message.mpim",
+        }
+    }
+}
+
+impl std::str::FromStr for ChannelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(Self::Channel),
+            "group" => Ok(Self::Group),
+            "im" => Ok(Self::Im),
+            "mpim" => Ok(Self::Mpim),
+            _ => Err(format!("unknown channel type: {}", s)),
+        }
+    }
+}
+
+pub struct RawConfig {
+    pub app_level_token: String,
+    pub user_oauth_token: String,
+    /// Echo messages which are edited to newly mention the bot.
+    pub echo_edited_mentions: bool,
+    /// Users allowed to run admin commands such as `pause` and `resume`.
+    pub admin_user_ids: HashSet<String>,
+    /// Escape `<!channel>`, `<@U…>` and friends in echoed text.
+    pub neutralize_mentions: bool,
+    /// Identifies this instance's connections in logs and the user agent.
+    pub connection_tag: Option<String>,
+    /// Formatting of echoes.
+    pub echo_format: EchoFormat,
+    /// Template of the echoed message, see [`crate::echo::echo_text`].
+    pub echo_template: String,
+    /// Frames of at least this many bytes are considered large.
+    pub large_frame_bytes: usize,
+    /// Fraction (0.0–1.0) of large frames whose size is logged.
+    pub large_frame_log_sample_rate: f64,
+    /// `parse` mode of posted messages.
+    pub message_parse: ParseMode,
+    /// Number of connection attempts kept for the `reconnects` admin command.
+    pub reconnect_history_size: usize,
+    /// Tell admins with an ephemeral message when a post fails for a missing
+    /// scope other than `chat:write`.
+    pub notify_admins_on_scope_error: bool,
+    /// `disconnect` reasons after which we exit instead of reconnecting.
+    pub fatal_disconnect_reasons: HashSet<String>,
+    /// `message` subtypes which are echoed like plain messages.
+    pub echo_message_subtypes: HashSet<String>,
+    /// Echo messages of other bots (never the bot's own).
+    pub respond_to_bots: bool,
+    /// How many times a Web API request is sent again after a transient error
+    /// such as an empty response.
+    pub transient_retries: u32,
+    /// Only echo the first message in each thread, staying quiet when the bot
+    /// is mentioned again there.
+    pub echo_once_per_thread: bool,
+    /// Length in characters echoes are truncated to after Slack rejected one
+    /// with `msg_too_long`.
+    pub safe_message_length: usize,
+    /// Fraction (0.0–1.0) of messages which are echoed, e.g. for load testing.
+    pub response_sample_rate: f64,
+    /// Seed of the sampling, random if unset.
+    pub response_sample_seed: Option<u64>,
+    /// Whether top level messages, thread replies or both are echoed.
+    pub echo_scope: EchoScope,
+    /// Messages with the same channel, user and text within this window are
+    /// echoed once. Disabled if unset.
+    pub content_dedup_window: Option<Duration>,
+    /// Messages shorter than this many characters, not counting mentions of
+    /// the bot, are not echoed.
+    pub min_echo_length: usize,
+    /// Icon of echoes as `:name:`.
+    pub icon_emoji: Option<String>,
+    /// Global cap on echoes a minute, as a safety net against reply loops.
+    pub max_echoes_per_minute: Option<u32>,
+    /// Message sent to new members of the workspace, where `{user}` mentions
+    /// them. Disabled if unset.
+    pub welcome_template: Option<String>,
+    /// Channels the bot works in. All channels if empty.
+    pub allowed_channels: HashSet<String>,
+    /// Channels the bot ignores, even if allowed.
+    pub denied_channels: HashSet<String>,
+    /// Join newly created public channels.
+    pub auto_join_new_channels: bool,
+    /// Warn at startup about scopes needed by enabled features which the token
+    /// lacks.
+    pub warn_missing_scopes: bool,
+    /// Connections are replaced after this long. Unlimited if unset.
+    pub max_connection_lifetime: Option<Duration>,
+    /// The bot's own messages, such as replies to admin commands.
+    pub messages: Messages,
+    /// Log and count `pin_added` and `star_added` events.
+    pub track_engagement: bool,
+    /// Minimum interval between echoes per channel or thread. Echoes sooner
+    /// are dropped.
+    pub reply_cooldown: Option<Duration>,
+    /// Whether the cooldown is per channel or per thread.
+    pub reply_cooldown_key: CooldownKey,
+    /// Envelopes with longer ids are skipped without an ack.
+    pub max_envelope_id_length: usize,
+    /// Bot tokens by team id for multi-team mode, where events are handled
+    /// with the token of the team they are authorized for.
+    pub team_tokens: HashMap<String, String>,
+    /// Echoes are held back for this long after `hello`.
+    pub warmup: Option<Duration>,
+    /// Ignore direct messages of users who aren't admins instead of echoing
+    /// them.
+    pub ignore_non_admin_dms: bool,
+    /// `apps.connections.open` calls taking at least this long are logged as
+    /// warnings.
+    pub slow_connections_open: Duration,
+    /// Channel types whose messages are echoed. Mentions (`app_mention`) have
+    /// no channel type, so theirs is told by the channel id, see
+    /// [`ChannelType::of_channel_id`].
+    pub echo_channel_types: HashSet<ChannelType>,
+    /// Echoes in the same channel within this window of the first one are
+    /// posted as a single message.
+    pub coalesce_window: Option<Duration>,
+    /// A combined message is posted early once it has this many echoes.
+    pub coalesce_max_echoes: usize,
+    /// How many connections may run their handshake at once.
+    pub max_concurrent_handshakes: usize,
+    /// Minimum time between the starts of two handshakes.
+    pub handshake_spacing: Duration,
+}
+
+/// Where settings are read from: the file named by `SLACK_CONFIG_FILE`, if
+/// any, then the environment.
+struct Vars {
+    file: HashMap<String, String>,
+    /// Whether to fall back to the environment, off in tests.
+    env: bool,
+}
+
+impl Vars {
+    fn load() -> Result<Self, String> {
+        let path = match std::env::var("SLACK_CONFIG_FILE") {
+            Ok(path) => path,
+            Err(_) => {
+                return Ok(Self {
+                    file: HashMap::new(),
+                    env: true,
+                })
+            }
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read the config file {}: {}", path, e))?;
+        Ok(Self {
+            file: parse_config_file(&content),
+            env: true,
+        })
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.file
+            .get(key)
+            .cloned()
+            .or_else(|| std::env::var(key).ok().filter(|_| self.env))
+    }
+
+    fn required(&self, key: &str) -> Result<String, String> {
+        self.var(key)
+            .ok_or_else(|| format!("Please set the environment variable {}", key))
+    }
+
+    fn list(&self, key: &str) -> HashSet<String> {
+        self.list_or(key, &[])
+    }
+
+    fn list_or(&self, key: &str, default: &[&str]) -> HashSet<String> {
+        let value = match self.var(key) {
+            Some(value) => value,
+            None => return default.iter().map(|v| v.to_string()).collect(),
+        };
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T, String> {
+        Ok(self.parse_opt(key)?.unwrap_or(default))
+    }
+
+    fn parse_opt<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.var(key)
+            .map(|v| {
+                v.parse().map_err(|_| {
+                    format!("Invalid value of the environment variable {}: {}", key, v)
+                })
+            })
+            .transpose()
+    }
+
+    /// A fraction between 0.0 and 1.0.
+    fn fraction(&self, key: &str, default: f64) -> Result<f64, String> {
+        let value = self.parse(key, default)?;
+        if !(0.0..=1.0).contains(&value) {
+            return Err(format!(
+                "Invalid value of the environment variable {}: {}",
+                key, value
+            ));
+        }
+        Ok(value)
+    }
+
+    /// The catalog named by `SLACK_MESSAGES_FILE`, or the built-in messages.
+    fn messages(&self) -> Result<Messages, String> {
+        let default_locale = self
+            .var("SLACK_DEFAULT_LOCALE")
+            .unwrap_or_else(|| "en".to_string());
+        let path = match self.var("SLACK_MESSAGES_FILE") {
+            Some(path) => path,
+            None => return Ok(Messages::new(default_locale)),
+        };
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read the messages file {}: {}", path, e))?;
+        Messages::from_json(default_locale, &json)
+            .map_err(|e| format!("Invalid messages file {}: {}", path, e))
+    }
+
+    /// Comma separated `key:value` pairs.
+    fn map(&self, key: &str) -> Result<HashMap<String, String>, String> {
+        self.list(key)
+            .into_iter()
+            .map(|pair| match pair.split_once(':') {
+                Some((k, v)) => Ok((k.trim().to_string(), v.trim().to_string())),
+                None => Err(format!(
+                    "Invalid value of the environment variable {}: expected key:value pairs",
+                    key
+                )),
+            })
+            .collect()
+    }
+
+    fn flag(&self, key: &str, default: bool) -> bool {
+        match self.var(key) {
+            Some(v) => matches!(v.to_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+            None => default,
+        }
+    }
+}
+
+/// Parses `KEY=VALUE` lines, skipping blank lines and `#` comments. Values may
+/// be quoted like in the README.
+fn parse_config_file(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+impl RawConfig {
+    /// Reads the config from `SLACK_CONFIG_FILE` and the environment.
+    pub fn load() -> Result<Self, String> {
+        Self::from_vars(Vars::load()?)
+    }
+
+    /// A config of only `vars`, with placeholder tokens unless given.
+    #[cfg(test)]
+    pub fn for_test(vars: &[(&str, &str)]) -> Result<Self, String> {
+        let file: HashMap<String, String> = [
+            ("SLACK_APP_LEVEL_TOKEN", "xapp-test"),
+            ("SLACK_USER_OAUTH_TOKEN", "xoxb-test"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        Self::from_vars(Vars { file, env: false })
+    }
+
+    fn from_vars(vars: Vars) -> Result<Self, String> {
+        let echo_format = vars.parse("SLACK_ECHO_FORMAT", EchoFormat::Code)?;
+        let mention_author = vars.flag("SLACK_MENTION_AUTHOR", false);
+        Ok(Self {
+            app_level_token: vars.required("SLACK_APP_LEVEL_TOKEN")?,
+            user_oauth_token: vars.required("SLACK_USER_OAUTH_TOKEN")?,
+            echo_edited_mentions: vars.flag("SLACK_ECHO_EDITED_MENTIONS", false),
+            admin_user_ids: vars.list("SLACK_ADMIN_USER_IDS"),
+            neutralize_mentions: vars.flag("SLACK_NEUTRALIZE_MENTIONS", true),
+            connection_tag: vars.var("SLACK_CONNECTION_TAG"),
+            echo_format,
+            echo_template: vars.var("SLACK_ECHO_TEMPLATE").unwrap_or_else(|| {
+                match (echo_format, mention_author) {
+                    (EchoFormat::Code, false) => DEFAULT_ECHO_TEMPLATE,
+                    (EchoFormat::Code, true) => DEFAULT_MENTION_ECHO_TEMPLATE,
+                    (EchoFormat::Plain, false) => DEFAULT_PLAIN_ECHO_TEMPLATE,
+                    (EchoFormat::Plain, true) => DEFAULT_PLAIN_MENTION_ECHO_TEMPLATE,
+                }
+                .to_string()
+            }),
+            large_frame_bytes: vars.parse("SLACK_LARGE_FRAME_BYTES", 16 * 1024)?,
+            large_frame_log_sample_rate: vars.fraction("SLACK_LARGE_FRAME_LOG_SAMPLE_RATE", 0.0)?,
+            message_parse: vars.parse("SLACK_MESSAGE_PARSE", ParseMode::None)?,
+            reconnect_history_size: vars.parse("SLACK_RECONNECT_HISTORY_SIZE", 50)?,
+            notify_admins_on_scope_error: vars.flag("SLACK_NOTIFY_ADMINS_ON_SCOPE_ERROR", false),
+            fatal_disconnect_reasons: vars
+                .list_or("SLACK_FATAL_DISCONNECT_REASONS", &["link_disabled"]),
+            echo_message_subtypes: vars.list_or(
+                "SLACK_ECHO_MESSAGE_SUBTYPES",
+                &["thread_broadcast", "file_share", "me_message"],
+            ),
+            respond_to_bots: vars.flag("SLACK_RESPOND_TO_BOTS", false),
+            transient_retries: vars.parse("SLACK_TRANSIENT_RETRIES", 1)?,
+            echo_once_per_thread: vars.flag("SLACK_ECHO_ONCE_PER_THREAD", false),
+            safe_message_length: vars.parse("SLACK_SAFE_MESSAGE_LENGTH", 4000)?,
+            response_sample_rate: vars.fraction("SLACK_RESPONSE_SAMPLE_RATE", 1.0)?,
+            response_sample_seed: vars.parse_opt("SLACK_RESPONSE_SAMPLE_SEED")?,
+            echo_scope: vars.parse("SLACK_ECHO_SCOPE", EchoScope::All)?,
+            content_dedup_window: vars
+                .parse_opt("SLACK_CONTENT_DEDUP_WINDOW_SECS")?
+                .map(Duration::from_secs),
+            min_echo_length: vars.parse("SLACK_MIN_ECHO_LENGTH", 0)?,
+            icon_emoji: vars
+                .var("SLACK_ICON_EMOJI")
+                .map(|name| normalize_emoji(&name))
+                .transpose()
+                .map_err(|e| format!("Invalid value of SLACK_ICON_EMOJI: {}", e))?,
+            max_echoes_per_minute: vars.parse_opt("SLACK_MAX_ECHOES_PER_MINUTE")?,
+            welcome_template: vars.var("SLACK_WELCOME_TEMPLATE"),
+            allowed_channels: vars.list("SLACK_ALLOWED_CHANNELS"),
+            denied_channels: vars.list("SLACK_DENIED_CHANNELS"),
+            auto_join_new_channels: vars.flag("SLACK_AUTO_JOIN_NEW_CHANNELS", false),
+            warn_missing_scopes: vars.flag("SLACK_WARN_MISSING_SCOPES", true),
+            max_connection_lifetime: vars
+                .parse_opt("SLACK_MAX_CONNECTION_LIFETIME_SECS")?
+                .map(Duration::from_secs),
+            messages: vars.messages()?,
+            track_engagement: vars.flag("SLACK_TRACK_ENGAGEMENT", false),
+            reply_cooldown: vars
+                .parse_opt("SLACK_REPLY_COOLDOWN_MS")?
+                .map(Duration::from_millis),
+            reply_cooldown_key: vars.parse("SLACK_REPLY_COOLDOWN_KEY", CooldownKey::Channel)?,
+            max_envelope_id_length: vars.parse("SLACK_MAX_ENVELOPE_ID_LENGTH", 64)?,
+            team_tokens: vars.map("SLACK_TEAM_TOKENS")?,
+            warmup: vars
+                .parse_opt("SLACK_WARMUP_MS")?
+                .map(Duration::from_millis),
+            ignore_non_admin_dms: vars.flag("SLACK_IGNORE_NON_ADMIN_DMS", false),
+            echo_channel_types: vars
+                .list_or(
+                    "SLACK_ECHO_CHANNEL_TYPES",
+                    &["channel", "group", "im", "mpim"],
+                )
+                .iter()
+                .map(|s| {
+                    s.parse()
+                        .map_err(|e| format!("Invalid SLACK_ECHO_CHANNEL_TYPES: {}", e))
+                })
+                .collect::<Result<_, _>>()?,
+            coalesce_window: vars
+                .parse_opt("SLACK_COALESCE_WINDOW_MS")?
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            coalesce_max_echoes: vars.parse("SLACK_COALESCE_MAX_ECHOES", 10)?,
+            max_concurrent_handshakes: vars.parse("SLACK_MAX_CONCURRENT_HANDSHAKES", 1)?,
+            handshake_spacing: Duration::from_millis(
+                vars.parse("SLACK_HANDSHAKE_SPACING_MS", 250)?,
+            ),
+            slow_connections_open: Duration::from_millis(
+                vars.parse("SLACK_SLOW_CONNECTIONS_OPEN_MS", 2000)?,
+            ),
+        })
+    }
+
+    /// Names of the settings which differ in `new`. Tokens are left out so
+    /// they don't end up in logs.
+    pub fn changed_settings(&self, new: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),* $(,)?) => {
+                $(if self.$field != new.$field {
+                    changed.push(stringify!($field));
+                })*
+            };
+        }
+        compare!(
+            echo_edited_mentions,
+            admin_user_ids,
+            neutralize_mentions,
+            connection_tag,
+            echo_format,
+            echo_template,
+            large_frame_bytes,
+            large_frame_log_sample_rate,
+            message_parse,
+            reconnect_history_size,
+            notify_admins_on_scope_error,
+            fatal_disconnect_reasons,
+            echo_message_subtypes,
+            respond_to_bots,
+            transient_retries,
+            echo_once_per_thread,
+            safe_message_length,
+            response_sample_rate,
+            response_sample_seed,
+            echo_scope,
+            content_dedup_window,
+            min_echo_length,
+            icon_emoji,
+            max_echoes_per_minute,
+            welcome_template,
+            allowed_channels,
+            denied_channels,
+            auto_join_new_channels,
+            warn_missing_scopes,
+            max_connection_lifetime,
+            messages,
+            track_engagement,
+            reply_cooldown,
+            reply_cooldown_key,
+            max_envelope_id_length,
+            warmup,
+            ignore_non_admin_dms,
+            slow_connections_open,
+            echo_channel_types,
+            coalesce_window,
+            coalesce_max_echoes,
+            max_concurrent_handshakes,
+            handshake_spacing,
+        );
+        changed
+    }
+
+    /// Scopes of the user OAuth token needed by the enabled features.
+    pub fn needed_scopes(&self) -> Vec<&'static str> {
+        let mut scopes = vec!["chat:write"];
+        if self.echo_template.contains("{time}") {
+            scopes.push("users:read");
+        }
+        if self.icon_emoji.is_some() {
+            scopes.push("chat:write.customize");
+        }
+        if self.welcome_template.is_some() {
+            scopes.push("im:write");
+        }
+        if self.auto_join_new_channels {
+            scopes.push("channels:join");
+        }
+        scopes
+    }
+
+    /// Whether `channel` passes the allow and deny lists.
+    pub fn channel_allowed(&self, channel: &str) -> bool {
+        !self.denied_channels.contains(channel)
+            && (self.allowed_channels.is_empty() || self.allowed_channels.contains(channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_files() {
+        let vars = parse_config_file(
+            "# comment\n\nSLACK_ECHO_FORMAT = plain\nSLACK_ECHO_TEMPLATE=\"a = {text}\"\nnot a setting\n",
+        );
+        assert_eq!(vars.len(), 2);
+        assert_eq!(vars["SLACK_ECHO_FORMAT"], "plain");
+        assert_eq!(vars["SLACK_ECHO_TEMPLATE"], "a = {text}");
+    }
+
+    #[test]
+    fn icon_emoji_is_validated_when_loading() {
+        let config = RawConfig::for_test(&[("SLACK_ICON_EMOJI", "robot_face")]).unwrap();
+        assert_eq!(config.icon_emoji.as_deref(), Some(":robot_face:"));
+        let error = RawConfig::for_test(&[("SLACK_ICON_EMOJI", "robot face")])
+            .err()
+            .unwrap();
+        assert!(
+            error.starts_with("Invalid value of SLACK_ICON_EMOJI"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn denied_channels_win_over_allowed_ones() {
+        let config = RawConfig::for_test(&[]).unwrap();
+        assert!(config.channel_allowed("C1"));
+        let config = RawConfig::for_test(&[
+            ("SLACK_ALLOWED_CHANNELS", "C1, C2"),
+            ("SLACK_DENIED_CHANNELS", "C2"),
+        ])
+        .unwrap();
+        assert!(config.channel_allowed("C1"));
+        assert!(!config.channel_allowed("C2"));
+        assert!(!config.channel_allowed("C3"));
+    }
+
+    #[test]
+    fn needed_scopes_follow_enabled_features() {
+        let config = RawConfig::for_test(&[]).unwrap();
+        assert_eq!(config.needed_scopes(), ["chat:write"]);
+        let config = RawConfig::for_test(&[
+            ("SLACK_ECHO_TEMPLATE", "{text} at {time}"),
+            ("SLACK_ICON_EMOJI", "robot_face"),
+            ("SLACK_WELCOME_TEMPLATE", "Welcome {user}!"),
+            ("SLACK_AUTO_JOIN_NEW_CHANNELS", "true"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.needed_scopes(),
+            [
+                "chat:write",
+                "users:read",
+                "chat:write.customize",
+                "im:write",
+                "channels:join"
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_rate_must_be_a_fraction() {
+        let config = RawConfig::for_test(&[("SLACK_RESPONSE_SAMPLE_RATE", "0.25")]).unwrap();
+        assert_eq!(config.response_sample_rate, 0.25);
+        for rate in ["-0.1", "1.5", "NaN", "half"] {
+            let error = RawConfig::for_test(&[("SLACK_RESPONSE_SAMPLE_RATE", rate)])
+                .err()
+                .unwrap();
+            assert!(
+                error.starts_with(
+                    "Invalid value of the environment variable SLACK_RESPONSE_SAMPLE_RATE"
+                ),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Collapses messages with the same content seen within a time window, e.g.
+/// the same message delivered through overlapping event subscriptions under
+/// different envelope ids.
+///
+/// Messages are keyed by channel, user, text and the window their ts falls
+/// in. Keys are forgotten once the window has passed.
+pub struct ContentDedup {
+    window: Duration,
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl ContentDedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `message` and returns `true` if the same content was already
+    /// seen within the window.
+    pub fn is_duplicate(&self, channel: &str, message: &serde_json::Value) -> bool {
+        let field = |name| message.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        let ts_secs: u64 = field("ts")
+            .split('.')
+            .next()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(0);
+        let bucket = ts_secs / self.window.as_secs().max(1);
+        let key = format!(
+            "{}\n{}\n{}\n{}",
+            channel,
+            field("user"),
+            bucket,
+            field("text")
+        );
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| now.duration_since(*at) < self.window);
+        seen.insert(key, now).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn collapses_the_same_content_within_the_window() {
+        let dedup = ContentDedup::new(Duration::from_secs(60));
+        let message = json!({"user": "U1", "text": "hi", "ts": "1640995200.000100"});
+        let resent = json!({"user": "U1", "text": "hi", "ts": "1640995200.000200"});
+        assert!(!dedup.is_duplicate("C1", &message));
+        assert!(dedup.is_duplicate("C1", &resent));
+        assert!(!dedup.is_duplicate("C2", &message));
+        let other_text = json!({"user": "U1", "text": "hello", "ts": "1640995200.000100"});
+        assert!(!dedup.is_duplicate("C1", &other_text));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::bounded_set::BoundedSet;
+use crate::config::{EchoScope, RawConfig};
+use crate::slack::SlackClient;
+
+pub fn mentions(text: &str, user_id: &str) -> bool {
+    text.contains(&format!("<@{}>", user_id))
+}
+
+/// Escapes Slack control sequences which would notify someone, such as
+/// `<!channel>`, `<!here>`, `<!subteam^S123>` and `<@U123>`, so echoing user
+/// text can't ping people. Links and channel references are left as is.
+pub fn neutralize_mentions(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let sequence = &rest[start..];
+        match sequence.find('>') {
+            Some(end) if sequence[1..].starts_with(['!', '@']) => {
+                out.push_str("&lt;");
+                out.push_str(&sequence[1..end]);
+                out.push_str("&gt;");
+                rest = &sequence[end + 1..];
+            }
+            _ => {
+                out.push('<');
+                rest = &sequence[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub const DEFAULT_ECHO_TEMPLATE: &str = "You said: ```{text}```";
+
+/// Default template when mentioning the author is enabled.
+pub const DEFAULT_MENTION_ECHO_TEMPLATE: &str = "{user} you said: ```{text}```";
+
+/// Default templates of the plain echo format, without any decoration.
+pub const DEFAULT_PLAIN_ECHO_TEMPLATE: &str = "{text}";
+pub const DEFAULT_PLAIN_MENTION_ECHO_TEMPLATE: &str = "{user} {text}";
+
+/// Replaces `{name}` placeholders with the value returned by `resolve`.
+///
+/// Substitution is done in a single pass so placeholders contained in the
+/// substituted values (e.g. a user typing `{time}`) are left alone. Unknown
+/// placeholders are kept verbatim.
+pub fn render_template(template: &str, resolve: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder
+            .find('}')
+            .and_then(|end| resolve(&placeholder[1..end]).map(|v| (end, v)))
+        {
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Length in characters of `text` without mentions of the bot and surrounding
+/// whitespace, after neutralizing mentions if enabled.
+pub fn echo_length(text: &str, bot_user_id: Option<&str>, neutralize: bool) -> usize {
+    let text = match bot_user_id {
+        Some(bot_user_id) => text.replace(&format!("<@{}>", bot_user_id), ""),
+        None => text.to_string(),
+    };
+    let text = if neutralize {
+        neutralize_mentions(&text)
+    } else {
+        text
+    };
+    text.trim().chars().count()
+}
+
+/// The first `max_chars` characters of `text`.
+pub fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Formats a Slack message ts in the given IANA timezone, falling back to UTC
+/// when the timezone is missing or unknown.
+pub fn format_local_time(ts: &str, tz: Option<&str>) -> Option<String> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let time = chrono::DateTime::from_timestamp(
+        secs.parse().ok()?,
+        micros.parse::<u32>().ok()?.checked_mul(1000)?,
+    )?;
+    let tz = tz
+        .and_then(|tz| tz.parse::<chrono_tz::Tz>().ok())
+        .unwrap_or(chrono_tz::UTC);
+    Some(
+        time.with_timezone(&tz)
+            .format("%Y-%m-%d %H:%M:%S %Z")
+            .to_string(),
+    )
+}
+
+/// Mention of the message author, or `None` if the author is a bot (this one
+/// or another).
+pub fn author_mention(message: &serde_json::Value, bot_user_id: Option<&str>) -> Option<String> {
+    if message.get("bot_id").is_some() {
+        return None;
+    }
+    let user = message.get("user").and_then(|v| v.as_str())?;
+    if Some(user) == bot_user_id {
+        return None;
+    }
+    Some(format!("<@{}>", user))
+}
+
+/// Renders the echo template for `message`.
+///
+/// Supported placeholders are:
+///
+/// - `{text}`: the message text, with mentions neutralized if enabled
+/// - `{time}`: the message time in the author's timezone (via `users.info`)
+/// - `{user}`: a mention of the author, empty for bots
+///
+/// `{user}` is substituted after neutralizing, so the author is mentioned even
+/// though mentions typed by users are not.
+pub async fn echo_text(
+    slack_client: &SlackClient,
+    config: &RawConfig,
+    bot_user_id: Option<&str>,
+    message: &serde_json::Value,
+) -> String {
+    let text = message
+        .get("text")
+        .and_then(|v| v.as_str())
+        .expect("Failed to get text");
+    let text = if config.neutralize_mentions {
+        neutralize_mentions(text)
+    } else {
+        text.to_string()
+    };
+
+    let time = if config.echo_template.contains("{time}") {
+        let tz = match message.get("user").and_then(|v| v.as_str()) {
+            Some(user) => match slack_client.users_info(user).await {
+                Ok(res) => res.user.tz,
+                Err(e) => {
+                    tracing::warn!("users.info failed: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        message
+            .get("ts")
+            .and_then(|v| v.as_str())
+            .and_then(|ts| format_local_time(ts, tz.as_deref()))
+    } else {
+        None
+    };
+
+    let user = author_mention(message, bot_user_id).unwrap_or_default();
+
+    render_template(&config.echo_template, |name| match name {
+        "text" => Some(text.clone()),
+        "time" => time.clone(),
+        "user" => Some(user.clone()),
+        _ => None,
+    })
+}
+
+/// Whether `message` is a reply in a thread, i.e. has a `thread_ts` other than
+/// its own `ts`. Thread parents have `thread_ts == ts`.
+pub fn is_thread_reply(message: &serde_json::Value) -> bool {
+    match message.get("thread_ts").and_then(|v| v.as_str()) {
+        Some(thread_ts) => message.get("ts").and_then(|v| v.as_str()) != Some(thread_ts),
+        None => false,
+    }
+}
+
+pub fn in_echo_scope(message: &serde_json::Value, scope: EchoScope) -> bool {
+    match scope {
+        EchoScope::All => true,
+        EchoScope::TopLevel => !is_thread_reply(message),
+        EchoScope::Threads => is_thread_reply(message),
+    }
+}
+
+/// Decides which message of an event to echo, if any.
+///
+/// Edits (`message_changed`) are only echoed when enabled and the edit newly
+/// mentions the bot. Every echoed message ts is remembered so later edits of
+/// the same message are not echoed again.
+///
+/// Other `message` events only get here from direct messages, the app having
+/// left mentions elsewhere to `app_mention`.
+///
+/// Other subtypes are only echoed if listed in the config. The rest, e.g.
+/// `message_deleted`, `channel_join` or notices about messages the bot posted
+/// or scheduled itself, are expected noise and skipped at debug level.
+pub fn message_to_echo<'e>(
+    event: &'e serde_json::Value,
+    config: &RawConfig,
+    bot_user_id: Option<&str>,
+    echoed: &mut BoundedSet,
+) -> Option<&'e serde_json::Value> {
+    let subtype = event.get("subtype").and_then(|v| v.as_str());
+    if subtype == Some("message_changed") {
+        let bot_user_id = bot_user_id.filter(|_| config.echo_edited_mentions)?;
+        let message = event.get("message")?;
+        let text = message.get("text").and_then(|v| v.as_str())?;
+        let previous_text = event
+            .get("previous_message")
+            .and_then(|v| v.get("text"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        if !mentions(text, bot_user_id) || mentions(previous_text, bot_user_id) {
+            return None;
+        }
+        let ts = message.get("ts").and_then(|v| v.as_str())?;
+        return echoed.insert(ts).then_some(message);
+    }
+    let echoed_subtype = |subtype: &str| {
+        config.echo_message_subtypes.contains(subtype)
+            || (subtype == "bot_message" && config.respond_to_bots)
+    };
+    if let Some(subtype) = subtype.filter(|s| !echoed_subtype(s)) {
+        tracing::debug!("Ignoring message of subtype {}", subtype);
+        return None;
+    }
+    if event.get("text").and_then(|v| v.as_str()).is_none() {
+        tracing::debug!("Ignoring message without text");
+        return None;
+    }
+
+    if let Some(ts) = event.get("ts").and_then(|v| v.as_str()) {
+        echoed.insert(ts);
+    }
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const BOT: &str = "UBOT";
+
+    #[test]
+    fn neutralizes_special_mentions() {
+        assert_eq!(neutralize_mentions("<!channel> hi"), "&lt;!channel&gt; hi");
+        assert_eq!(neutralize_mentions("hey <!here>"), "hey &lt;!here&gt;");
+        assert_eq!(
+            neutralize_mentions("<!subteam^S123|@team> look"),
+            "&lt;!subteam^S123|@team&gt; look"
+        );
+        assert_eq!(neutralize_mentions("<@U123>"), "&lt;@U123&gt;");
+    }
+
+    #[test]
+    fn keeps_links_and_channels() {
+        let text = "see <https://example.com|this> in <#C123|general> and a < b";
+        assert_eq!(neutralize_mentions(text), text);
+    }
+
+    fn to_echo(event: serde_json::Value, vars: &[(&str, &str)]) -> Option<serde_json::Value> {
+        let config = RawConfig::for_test(vars).unwrap();
+        let mut echoed = BoundedSet::new(16);
+        message_to_echo(&event, &config, Some(BOT), &mut echoed).cloned()
+    }
+
+    #[test]
+    fn scopes_tell_thread_parents_from_replies() {
+        let top_level = json!({"text": "hi", "ts": "1.0"});
+        let parent = json!({"text": "hi", "ts": "1.0", "thread_ts": "1.0"});
+        let reply = json!({"text": "hi", "ts": "2.0", "thread_ts": "1.0"});
+        let in_scope =
+            |scope| [&top_level, &parent, &reply].map(|message| in_echo_scope(message, scope));
+        assert_eq!(in_scope(EchoScope::All), [true, true, true]);
+        assert_eq!(in_scope(EchoScope::TopLevel), [true, true, false]);
+        assert_eq!(in_scope(EchoScope::Threads), [false, false, true]);
+    }
+
+    #[test]
+    fn echo_length_leaves_out_mentions_of_the_bot() {
+        assert_eq!(echo_length("<@UBOT> ?", Some(BOT), true), 1);
+        assert_eq!(echo_length("  <@UBOT>  ", Some(BOT), true), 0);
+        assert_eq!(echo_length("<@UBOT> héllo", Some(BOT), false), 5);
+        // Escaped mentions count as escaped.
+        assert_eq!(echo_length("<!here>", Some(BOT), true), 13);
+        assert_eq!(echo_length("<!here>", Some(BOT), false), 7);
+    }
+
+    #[test]
+    fn truncates_by_characters() {
+        assert_eq!(truncate_chars("héllo wörld", 5), "héllo");
+        assert_eq!(truncate_chars("héllo", 5), "héllo");
+        assert_eq!(truncate_chars("héllo", 0), "");
+    }
+
+    #[test]
+    fn renders_placeholders_in_one_pass() {
+        let rendered = render_template(
+            "{user} said {text} at {time} {unknown}",
+            |name| match name {
+                "user" => Some("<@U1>".to_string()),
+                "text" => Some("{time}".to_string()),
+                "time" => Some("noon".to_string()),
+                _ => None,
+            },
+        );
+        assert_eq!(rendered, "<@U1> said {time} at noon {unknown}");
+    }
+
+    #[test]
+    fn formats_time_in_the_user_timezone() {
+        // 2022-01-01 00:00:00 UTC
+        let ts = "1640995200.000100";
+        assert_eq!(
+            format_local_time(ts, Some("Asia/Tokyo")).as_deref(),
+            Some("2022-01-01 09:00:00 JST")
+        );
+        assert_eq!(
+            format_local_time(ts, Some("America/New_York")).as_deref(),
+            Some("2021-12-31 19:00:00 EST")
+        );
+    }
+
+    #[test]
+    fn formats_time_in_utc_without_a_known_timezone() {
+        let ts = "1640995200.000100";
+        let utc = Some("2022-01-01 00:00:00 UTC");
+        assert_eq!(format_local_time(ts, None).as_deref(), utc);
+        assert_eq!(format_local_time(ts, Some("Mars/Olympus")).as_deref(), utc);
+        assert_eq!(format_local_time("not a ts", None), None);
+    }
+
+    fn client() -> SlackClient {
+        SlackClient {
+            token: "xoxb-test".to_string(),
+            parse: crate::slack::ParseMode::None,
+            transient_retries: 0,
+            api_base: crate::slack::SLACK_API_BASE.to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn user_placeholder_mentions_the_author_despite_neutralizing() {
+        let config = RawConfig::for_test(&[("SLACK_MENTION_AUTHOR", "true")]).unwrap();
+        let message = json!({"user": "U1", "text": "<@UBOT> ping <@U2> <!channel>", "ts": "1.0"});
+        assert_eq!(
+            echo_text(&client(), &config, Some(BOT), &message).await,
+            "<@U1> you said: ```&lt;@UBOT&gt; ping &lt;@U2&gt; &lt;!channel&gt;```"
+        );
+    }
+
+    #[async_std::test]
+    async fn user_placeholder_is_empty_for_bots() {
+        let config = RawConfig::for_test(&[("SLACK_ECHO_TEMPLATE", "[{user}] {text}")]).unwrap();
+        let bot_message = json!({"user": "U9", "bot_id": "B9", "text": "beep", "ts": "1.0"});
+        assert_eq!(
+            echo_text(&client(), &config, Some(BOT), &bot_message).await,
+            "[] beep"
+        );
+        let own_message = json!({"user": BOT, "text": "me", "ts": "1.0"});
+        assert_eq!(
+            echo_text(&client(), &config, Some(BOT), &own_message).await,
+            "[] me"
+        );
+    }
+
+    #[test]
+    fn mention_is_echoed() {
+        let mention =
+            json!({"type": "app_mention", "channel": "C1", "text": "<@UBOT> hi", "ts": "1.0"});
+        assert_eq!(to_echo(mention.clone(), &[]), Some(mention));
+    }
+
+    #[test]
+    fn direct_message_is_echoed() {
+        let event = json!({
+            "type": "message",
+            "channel_type": "im",
+            "channel": "D1",
+            "text": "hi",
+            "ts": "1.0",
+        });
+        assert_eq!(to_echo(event.clone(), &[]), Some(event));
+    }
+
+    #[test]
+    fn edit_newly_mentioning_the_bot_is_echoed() {
+        let event = json!({
+            "type": "message",
+            "subtype": "message_changed",
+            "channel_type": "channel",
+            "channel": "C1",
+            "message": {"text": "<@UBOT> hi", "ts": "1.0"},
+            "previous_message": {"text": "hi", "ts": "1.0"},
+        });
+        let vars = [("SLACK_ECHO_EDITED_MENTIONS", "true")];
+        assert_eq!(
+            to_echo(event.clone(), &vars),
+            Some(event["message"].clone())
+        );
+        assert_eq!(to_echo(event, &[]), None);
+    }
+}
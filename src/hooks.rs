@@ -0,0 +1,44 @@
+use crate::interactive::ViewClosed;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type HookFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Runs custom logic after an echo was posted, e.g. confirming it with an
+/// external system for critical channels.
+///
+/// The hook runs once the post succeeded (including a retry after truncating
+/// it) and before the handler returns, so its time counts towards handling the
+/// event, and the outcome is recorded on the event span only after the hook
+/// finished. A failing hook is logged and the echo still counts as echoed.
+pub trait AfterSend: Send + Sync {
+    /// `channel` and `ts` identify the posted message, `text` is the text as
+    /// posted, i.e. after truncation.
+    fn after_send<'a>(&'a self, channel: &'a str, ts: &'a str, text: &'a str) -> HookFuture<'a>;
+}
+
+/// The default hook, doing nothing.
+pub struct NoopAfterSend;
+
+impl AfterSend for NoopAfterSend {
+    fn after_send<'a>(&'a self, _: &'a str, _: &'a str, _: &'a str) -> HookFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Reacts to a user dismissing a modal, e.g. to clean up state kept for it.
+///
+/// The envelope is acked before the handler runs; `view_closed` takes no
+/// response. A failing handler is logged.
+pub trait ViewClosedHandler: Send + Sync {
+    fn view_closed<'a>(&'a self, closed: &'a ViewClosed) -> HookFuture<'a>;
+}
+
+/// The default handler, doing nothing.
+pub struct NoopViewClosed;
+
+impl ViewClosedHandler for NoopViewClosed {
+    fn view_closed<'a>(&'a self, _: &'a ViewClosed) -> HookFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -0,0 +1,93 @@
+use serde::Deserialize;
+
+/// Payload of an interactive envelope, by its `type`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum InteractivePayload {
+    /// A user dismissed a modal opened with `notify_on_close`.
+    ViewClosed(ViewClosed),
+    /// `view_submission`, `block_actions` and the like, which the bot doesn't
+    /// handle.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ViewClosed {
+    pub user: InteractiveUser,
+    pub view: View,
+    /// Whether the whole view stack was closed rather than only this view.
+    #[serde(default)]
+    pub is_cleared: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractiveUser {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct View {
+    pub id: String,
+    pub callback_id: Option<String>,
+    /// Whatever the view was opened with, e.g. an id of state to clean up.
+    pub private_metadata: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_view_closed() {
+        let payload = json!({
+            "type": "view_closed",
+            "team": {"id": "T1", "domain": "example"},
+            "user": {"id": "U1", "name": "someone"},
+            "view": {
+                "id": "V1",
+                "type": "modal",
+                "callback_id": "survey",
+                "private_metadata": "draft-42",
+            },
+            "api_app_id": "A1",
+            "is_cleared": true,
+        });
+        match serde_json::from_value(payload).unwrap() {
+            InteractivePayload::ViewClosed(closed) => {
+                assert_eq!(closed.user.id, "U1");
+                assert_eq!(closed.view.id, "V1");
+                assert_eq!(closed.view.callback_id.as_deref(), Some("survey"));
+                assert_eq!(closed.view.private_metadata.as_deref(), Some("draft-42"));
+                assert!(closed.is_cleared);
+            }
+            other => panic!("Parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn is_cleared_defaults_to_false() {
+        let payload = json!({
+            "type": "view_closed",
+            "user": {"id": "U1"},
+            "view": {"id": "V1"},
+        });
+        match serde_json::from_value(payload).unwrap() {
+            InteractivePayload::ViewClosed(closed) => {
+                assert!(!closed.is_cleared);
+                assert_eq!(closed.view.callback_id, None);
+            }
+            other => panic!("Parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_types_are_not_handled() {
+        let payload = json!({"type": "block_actions", "actions": []});
+        assert!(matches!(
+            serde_json::from_value(payload).unwrap(),
+            InteractivePayload::Other
+        ));
+    }
+}
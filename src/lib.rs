@@ -0,0 +1,148 @@
+pub mod admin;
+pub mod app;
+pub mod bounded_set;
+pub mod coalesce;
+pub mod config;
+pub mod dedup;
+pub mod echo;
+pub mod hooks;
+pub mod interactive;
+pub mod messages;
+pub mod ordering;
+pub mod reconnect;
+pub mod slack;
+pub mod socket_mode;
+pub mod stats;
+#[cfg(test)]
+mod test_server;
+pub mod throttle;
+
+use crate::app::App;
+use crate::reconnect::{Backoff, ReconnectEvent, ReconnectOutcome};
+use crate::socket_mode::{ConnectionEnd, LoopConfig, WebSocketStream};
+use std::sync::Arc;
+use tracing::Instrument;
+
+/// Keeps a socket mode connection open, reconnecting with backoff, until the
+/// inspection hook of `loop_config` stops it (`Ok`) or Slack tells us not to
+/// reconnect (`Err` with the reason).
+pub async fn serve(app: &Arc<App>, loop_config: &LoopConfig) -> Result<(), String> {
+    let mut backoff = Backoff::default();
+    let mut reason = "startup".to_string();
+    // Set when a rotation already opened the next connection.
+    let mut next_stream = None;
+    loop {
+        let (stream, attempt) = match next_stream.take() {
+            Some(stream) => (stream, backoff.attempt()),
+            None => {
+                let delay = backoff.delay();
+                let attempt = backoff.attempt();
+                if !delay.is_zero() {
+                    tracing::info!("Reconnecting in {:?} (attempt {})", delay, attempt);
+                    async_std::task::sleep(delay).await;
+                }
+
+                let result = socket_mode::open(app).await;
+                app.reconnects.lock().unwrap().push(ReconnectEvent {
+                    at: chrono::Utc::now().to_rfc3339(),
+                    reason: std::mem::take(&mut reason),
+                    attempt,
+                    backoff_ms: delay.as_millis() as u64,
+                    outcome: match &result {
+                        Ok(_) => ReconnectOutcome::Connected,
+                        Err(e) => ReconnectOutcome::Failed(e.clone()),
+                    },
+                });
+                match result {
+                    Ok(stream) => (stream, attempt),
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        backoff.failed();
+                        reason = format!("connect failed: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let span = tracing::info_span!("connection", attempt);
+        let end = socket_mode::run(app, loop_config, stream, &mut backoff)
+            .instrument(span)
+            .await;
+        match after_connection(end, &mut backoff) {
+            AfterConnection::Reconnect(r) => reason = r,
+            AfterConnection::Resume(stream) => {
+                app.reconnects.lock().unwrap().push(ReconnectEvent {
+                    at: chrono::Utc::now().to_rfc3339(),
+                    reason: "rotation".to_string(),
+                    attempt: backoff.attempt(),
+                    backoff_ms: 0,
+                    outcome: ReconnectOutcome::Connected,
+                });
+                next_stream = Some(*stream);
+            }
+            AfterConnection::Return(result) => return result,
+        }
+    }
+}
+
+/// What [`serve`] does once a connection ended.
+enum AfterConnection {
+    /// Connect again, for the given reason.
+    Reconnect(String),
+    /// Carry on with the connection a rotation already opened.
+    Resume(Box<WebSocketStream>),
+    /// Stop serving.
+    Return(Result<(), String>),
+}
+
+/// Decides what to do after a connection ended with `end`, counting failed
+/// connections in `backoff`.
+fn after_connection(end: ConnectionEnd, backoff: &mut Backoff) -> AfterConnection {
+    match end {
+        ConnectionEnd::Disconnect(r) => AfterConnection::Reconnect(format!("disconnect: {}", r)),
+        ConnectionEnd::Error(e) => {
+            tracing::error!("{}", e);
+            backoff.failed();
+            AfterConnection::Reconnect(e)
+        }
+        ConnectionEnd::Fatal(e) => AfterConnection::Return(Err(e)),
+        ConnectionEnd::StreamEnded => {
+            tracing::warn!("Stream ended unexpectedly");
+            // Counted as a failure in case it keeps ending before `hello`.
+            backoff.failed();
+            AfterConnection::Reconnect("stream ended unexpectedly".to_string())
+        }
+        ConnectionEnd::Rotated(stream) => AfterConnection::Resume(stream),
+        ConnectionEnd::Stopped => AfterConnection::Return(Ok(())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn stream_endings_are_reconnected_from_with_backoff() {
+        let mut backoff = Backoff::default();
+        for delay in [1, 2] {
+            let next = after_connection(ConnectionEnd::StreamEnded, &mut backoff);
+            assert!(matches!(
+                next,
+                AfterConnection::Reconnect(reason) if reason == "stream ended unexpectedly"
+            ));
+            assert_eq!(backoff.delay(), Duration::from_secs(delay));
+        }
+        // Slack asking to reconnect isn't a failure.
+        let next = after_connection(
+            ConnectionEnd::Disconnect("refresh_requested".to_string()),
+            &mut backoff,
+        );
+        assert!(matches!(
+            next,
+            AfterConnection::Reconnect(reason) if reason == "disconnect: refresh_requested"
+        ));
+        assert_eq!(backoff.delay(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+/// Built-in messages in English, by key. Placeholders are filled with
+/// [`crate::echo::render_template`].
+const ENGLISH: &[(&str, &str)] = &[
+    (
+        "not_authorized",
+        "You are not authorized to run this command.",
+    ),
+    ("paused", "Paused. Use the `resume` command to continue."),
+    ("resumed", "Resumed."),
+    (
+        "scope_error_notice",
+        "I couldn't reply in this channel: {error}",
+    ),
+    ("reloaded", "Reloaded the config. Changed: {changed}"),
+    (
+        "reload_failed",
+        "Failed to reload the config, keeping the current one: {error}",
+    ),
+    ("reconnecting", "Reconnecting."),
+];
+
+/// Catalog of the bot's own messages by locale, such as `ja` or `pt-BR`.
+#[derive(Debug, PartialEq)]
+pub struct Messages {
+    /// Locale used when the user's locale is unknown or has no translation.
+    default_locale: String,
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Messages {
+    /// Only the built-in messages.
+    pub fn new(default_locale: String) -> Self {
+        Self {
+            default_locale,
+            locales: HashMap::new(),
+        }
+    }
+
+    /// Parses a JSON object of locales to objects of message keys to texts,
+    /// e.g. `{"ja": {"resumed": "再開しました。"}}`. Keys must be of built-in
+    /// messages, so that typos don't go unnoticed.
+    pub fn from_json(default_locale: String, json: &str) -> Result<Self, String> {
+        let locales: HashMap<String, HashMap<String, String>> =
+            serde_json::from_str(json).map_err(|e| e.to_string())?;
+        for (locale, messages) in &locales {
+            if let Some(key) = messages
+                .keys()
+                .find(|key| !ENGLISH.iter().any(|(k, _)| k == key))
+            {
+                return Err(format!("unknown message key {:?} in {}", key, locale));
+            }
+        }
+        Ok(Self {
+            default_locale,
+            locales,
+        })
+    }
+
+    /// Whether there are any translations, i.e. whether the user's locale
+    /// matters.
+    pub fn is_localized(&self) -> bool {
+        !self.locales.is_empty()
+    }
+
+    /// The message `key` in `locale`, falling back to its language (`pt` for
+    /// `pt-BR`), then the default locale and finally English.
+    pub fn get(&self, key: &str, locale: Option<&str>) -> &str {
+        let language = locale.and_then(|locale| locale.split(['-', '_']).next());
+        [locale, language, Some(self.default_locale.as_str())]
+            .into_iter()
+            .flatten()
+            .find_map(|locale| self.locales.get(locale)?.get(key))
+            .map(String::as_str)
+            .or_else(|| {
+                ENGLISH
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, text)| *text)
+            })
+            .unwrap_or_else(|| panic!("Unknown message key {}", key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_the_language_default_locale_and_english() {
+        let messages = Messages::from_json(
+            "ja".to_string(),
+            r#"{"ja": {"resumed": "再開しました。"}, "pt": {"resumed": "Retomado."}}"#,
+        )
+        .unwrap();
+        assert_eq!(messages.get("resumed", Some("pt-BR")), "Retomado.");
+        assert_eq!(messages.get("resumed", Some("de-DE")), "再開しました。");
+        assert_eq!(messages.get("resumed", None), "再開しました。");
+        assert_eq!(
+            messages.get("not_authorized", Some("pt-BR")),
+            "You are not authorized to run this command."
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err =
+            Messages::from_json("en".to_string(), r#"{"ja": {"resume": "再開"}}"#).unwrap_err();
+        assert_eq!(err, r#"unknown message key "resume" in ja"#);
+    }
+
+    #[test]
+    fn every_key_looked_up_is_built_in() {
+        let src = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(src).unwrap() {
+            let code: String = std::fs::read_to_string(entry.unwrap().path())
+                .unwrap()
+                .split_whitespace()
+                .collect();
+            for lookup in code.split("messages.get(\"").skip(1) {
+                keys.push(lookup.split('"').next().unwrap().to_string());
+            }
+        }
+        assert!(keys.len() >= ENGLISH.len(), "{:?}", keys);
+        for key in keys {
+            assert!(ENGLISH.iter().any(|(k, _)| *k == key), "{}", key);
+        }
+    }
+}
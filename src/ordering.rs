@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs jobs with the same key one after another, in submission order, while
+/// jobs with different keys run concurrently.
+///
+/// Each key with pending jobs has a worker task draining its queue; the worker
+/// exits (and the key is forgotten) once the queue is empty.
+#[derive(Default)]
+pub struct KeyedQueue {
+    queues: Mutex<HashMap<String, async_std::channel::Sender<Job>>>,
+}
+
+impl KeyedQueue {
+    pub fn spawn(self: &Arc<Self>, key: String, job: impl Future<Output = ()> + Send + 'static) {
+        let job: Job = Box::pin(job);
+        let mut queues = self.queues.lock().unwrap();
+        let job = match queues.get(&key) {
+            Some(sender) => match sender.try_send(job) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            },
+            None => job,
+        };
+
+        let (sender, receiver) = async_std::channel::unbounded();
+        sender
+            .try_send(job)
+            .unwrap_or_else(|_| unreachable!("receiver is alive"));
+        queues.insert(key.clone(), sender);
+        let this = Arc::clone(self);
+        async_std::task::spawn(async move { this.work(key, receiver).await });
+    }
+
+    async fn work(&self, key: String, receiver: async_std::channel::Receiver<Job>) {
+        loop {
+            while let Ok(job) = receiver.try_recv() {
+                job.await;
+            }
+            // Jobs are only queued with the lock held, so nothing can slip in
+            // between this check and forgetting the key.
+            let mut queues = self.queues.lock().unwrap();
+            if receiver.is_empty() {
+                queues.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn runs_jobs_of_a_key_in_order_and_keys_concurrently() {
+        let queue = Arc::new(KeyedQueue::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (done, finished) = async_std::channel::unbounded();
+        for (key, i, delay_ms) in [("a", 1, 30), ("a", 2, 0), ("b", 1, 0), ("a", 3, 10)] {
+            let log = Arc::clone(&log);
+            let done = done.clone();
+            queue.spawn(key.to_string(), async move {
+                async_std::task::sleep(Duration::from_millis(delay_ms)).await;
+                log.lock().unwrap().push(format!("{}{}", key, i));
+                done.send(()).await.unwrap();
+            });
+        }
+        for _ in 0..4 {
+            finished.recv().await.unwrap();
+        }
+        let log = log.lock().unwrap();
+        let of_a: Vec<_> = log.iter().filter(|job| job.starts_with('a')).collect();
+        assert_eq!(of_a, ["a1", "a2", "a3"]);
+        // `b` didn't wait for the slow first job of `a`.
+        assert_eq!(log[0], "b1");
+    }
+}
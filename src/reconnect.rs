@@ -0,0 +1,285 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Exponential backoff between failed connection attempts.
+#[derive(Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    const BASE: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    /// Delay before the next attempt, zero unless the last attempt failed.
+    pub fn delay(&self) -> Duration {
+        match self.failures {
+            0 => Duration::ZERO,
+            n => Self::BASE
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(Self::MAX),
+        }
+    }
+
+    /// Number of the next attempt since the last successful connection.
+    pub fn attempt(&self) -> u32 {
+        self.failures + 1
+    }
+
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Limits how many connection handshakes (`apps.connections.open` and the
+/// websocket upgrade) run at once and spaces out their starts, so connections
+/// dropping together don't all reconnect at the same moment.
+///
+/// Each connection still backs off and retries on its own; it only waits here
+/// for its turn.
+pub struct HandshakeGate {
+    /// Holds one item per free permit.
+    permits: (
+        async_std::channel::Sender<()>,
+        async_std::channel::Receiver<()>,
+    ),
+    /// Earliest start of the next handshake.
+    next_start: Mutex<Instant>,
+}
+
+impl HandshakeGate {
+    pub fn new(concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        let permits = async_std::channel::bounded(concurrency);
+        for _ in 0..concurrency {
+            permits
+                .0
+                .try_send(())
+                .unwrap_or_else(|_| unreachable!("channel has room"));
+        }
+        Self {
+            permits,
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Runs `handshake` once a permit is free and at least `spacing` passed
+    /// since the previous handshake started.
+    pub async fn run<F: Future>(&self, spacing: Duration, handshake: F) -> F::Output {
+        self.permits
+            .1
+            .recv()
+            .await
+            .unwrap_or_else(|_| unreachable!("sender is alive"));
+        let _permit = Permit(&self.permits.0);
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + spacing;
+            start
+        };
+        let wait = start.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            tracing::debug!("Waiting {:?} before the handshake", wait);
+            async_std::task::sleep(wait).await;
+        }
+        handshake.await
+    }
+}
+
+/// Returns its permit to the gate when dropped, even if the handshake is
+/// cancelled.
+struct Permit<'g>(&'g async_std::channel::Sender<()>);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "result", content = "error")]
+pub enum ReconnectOutcome {
+    Connected,
+    Failed(String),
+}
+
+/// A single connection attempt.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReconnectEvent {
+    /// RFC 3339 time of the attempt.
+    pub at: String,
+    /// Why we (re)connected, e.g. `startup` or `disconnect: refresh_requested`.
+    pub reason: String,
+    pub attempt: u32,
+    pub backoff_ms: u64,
+    pub outcome: ReconnectOutcome,
+}
+
+/// The most recent connection attempts, oldest first.
+pub struct ReconnectHistory {
+    capacity: usize,
+    events: VecDeque<ReconnectEvent>,
+}
+
+impl ReconnectHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, event: ReconnectEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.events).expect("Failed to serialize reconnect history")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn event(reason: &str) -> ReconnectEvent {
+        ReconnectEvent {
+            at: "2024-01-01T00:00:00+00:00".to_string(),
+            reason: reason.to_string(),
+            attempt: 1,
+            backoff_ms: 0,
+            outcome: ReconnectOutcome::Connected,
+        }
+    }
+
+    fn reasons(history: &ReconnectHistory) -> Vec<serde_json::Value> {
+        let events: serde_json::Value = serde_json::from_str(&history.to_json()).unwrap();
+        events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["reason"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_until_reset() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        assert_eq!(backoff.attempt(), 1);
+        let delays: Vec<_> = (0..8)
+            .map(|_| {
+                backoff.failed();
+                backoff.delay().as_secs()
+            })
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff.attempt(), 9);
+        backoff.reset();
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        assert_eq!(backoff.attempt(), 1);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_events() {
+        let mut history = ReconnectHistory::new(2);
+        for reason in ["startup", "disconnect: warning", "rotation"] {
+            history.push(event(reason));
+        }
+        assert_eq!(reasons(&history), ["disconnect: warning", "rotation"]);
+
+        let mut history = ReconnectHistory::new(0);
+        history.push(event("startup"));
+        assert!(reasons(&history).is_empty());
+    }
+
+    #[test]
+    fn history_is_serialized_with_tagged_outcomes() {
+        let mut history = ReconnectHistory::new(2);
+        history.push(ReconnectEvent {
+            outcome: ReconnectOutcome::Failed("invalid_auth".to_string()),
+            attempt: 2,
+            backoff_ms: 1000,
+            ..event("connect failed: invalid_auth")
+        });
+        history.push(event("startup"));
+        let events: serde_json::Value = serde_json::from_str(&history.to_json()).unwrap();
+        assert_eq!(
+            events,
+            serde_json::json!([
+                {
+                    "at": "2024-01-01T00:00:00+00:00",
+                    "reason": "connect failed: invalid_auth",
+                    "attempt": 2,
+                    "backoff_ms": 1000,
+                    "outcome": {"result": "failed", "error": "invalid_auth"},
+                },
+                {
+                    "at": "2024-01-01T00:00:00+00:00",
+                    "reason": "startup",
+                    "attempt": 1,
+                    "backoff_ms": 0,
+                    "outcome": {"result": "connected"},
+                },
+            ])
+        );
+    }
+
+    #[async_std::test]
+    async fn handshakes_run_at_most_concurrency_at_once() {
+        let gate = Arc::new(HandshakeGate::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let handshakes: Vec<_> = (0..5)
+            .map(|_| {
+                let (gate, running, most_running) = (
+                    Arc::clone(&gate),
+                    Arc::clone(&running),
+                    Arc::clone(&most_running),
+                );
+                async_std::task::spawn(async move {
+                    gate.run(Duration::ZERO, async {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_running.fetch_max(now, Ordering::SeqCst);
+                        async_std::task::sleep(Duration::from_millis(20)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await
+                })
+            })
+            .collect();
+        futures_util::future::join_all(handshakes).await;
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn handshake_starts_are_spaced_out() {
+        let gate = HandshakeGate::new(3);
+        let spacing = Duration::from_millis(30);
+        let before = Instant::now();
+        let starts = futures_util::future::join_all(
+            (0..3).map(|_| gate.run(spacing, async { Instant::now() })),
+        )
+        .await;
+        // The third handshake waits for two spacings even though there are
+        // enough permits.
+        let last = starts.into_iter().max().unwrap();
+        assert!(last - before >= spacing * 2, "{:?}", last - before);
+    }
+}
@@ -0,0 +1,766 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ResponseMetadata {
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenConnectionsResponse {
+    pub url: Option<String>,
+    /// Comma separated warnings, e.g. `superfluous_charset`.
+    warning: Option<String>,
+    #[serde(default)]
+    response_metadata: ResponseMetadata,
+    /// How long the call took.
+    #[serde(skip)]
+    pub elapsed: Duration,
+    /// HTTP status of the response.
+    #[serde(skip)]
+    pub status: u16,
+}
+
+impl OpenConnectionsResponse {
+    /// Warnings of both the `warning` field and `response_metadata`.
+    pub fn warnings(&self) -> Vec<&str> {
+        let mut warnings: Vec<&str> = self
+            .warning
+            .iter()
+            .flat_map(|w| w.split(','))
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .collect();
+        for w in &self.response_metadata.warnings {
+            if !warnings.contains(&w.as_str()) {
+                warnings.push(w);
+            }
+        }
+        warnings
+    }
+}
+
+/// User agent identifying this bot, suffixed with the connection tag (if any)
+/// so our connections can be told apart on Slack's side.
+pub fn user_agent(connection_tag: Option<&str>) -> String {
+    let base = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    match connection_tag {
+        Some(tag) => format!("{} ({})", base, tag),
+        None => base.to_string(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthTestResponse {
+    pub user_id: Option<String>,
+    /// Only set for bot tokens.
+    pub bot_id: Option<String>,
+    /// Scopes granted to the token, from the `x-oauth-scopes` header.
+    #[serde(skip)]
+    pub scopes: Option<HashSet<String>>,
+}
+
+/// Parses the comma separated scopes of an `x-oauth-scopes` header.
+pub fn parse_scopes(header: &str) -> HashSet<String> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// A decoded Web API response.
+struct ApiResponse<T> {
+    body: T,
+    /// `x-oauth-scopes` header, set by Slack for some tokens.
+    oauth_scopes: Option<String>,
+    status: u16,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsersInfoResponse {
+    pub user: SlackUser,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SlackUser {
+    /// IANA timezone name such as `Asia/Tokyo`.
+    pub tz: Option<String>,
+    /// IETF language tag such as `ja-JP`, only set when asked for.
+    pub locale: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PostMessageResponse {
+    /// Id of the channel the message was posted in.
+    pub channel: String,
+    pub ts: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConversationsOpenResponse {
+    pub channel: SlackChannel,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SlackChannel {
+    pub id: String,
+}
+
+/// Body of a Web API response with `ok: false`.
+#[derive(Deserialize, Debug)]
+pub struct ApiError {
+    #[serde(default = "unknown_error")]
+    pub error: String,
+    /// Scope required by the method, set for `missing_scope`.
+    pub needed: Option<String>,
+    /// Scopes granted to the token, set for `missing_scope`.
+    pub provided: Option<String>,
+}
+
+fn unknown_error() -> String {
+    "Unknown error".to_string()
+}
+
+#[derive(Debug)]
+pub enum SlackErrorKind {
+    /// The request failed or the body couldn't be read.
+    Http(surf::Error),
+    /// Slack answered with `ok: false`.
+    Api(ApiError),
+    /// The response didn't have the expected shape.
+    Decode(serde_json::Error),
+    /// The response had an empty body, which happens now and then and is worth
+    /// retrying.
+    EmptyBody { status: u16 },
+    /// The body wasn't JSON, e.g. because it was truncated.
+    InvalidBody {
+        status: u16,
+        /// The start of the body.
+        snippet: String,
+        error: serde_json::Error,
+    },
+    /// Slack endpoints don't redirect, so a 3xx likely comes from a captive
+    /// portal or proxy. Redirects aren't followed.
+    Redirect {
+        status: u16,
+        location: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub struct SlackError {
+    pub kind: SlackErrorKind,
+    /// `x-slack-req-id` header of the response, which Slack support asks for.
+    pub req_id: Option<String>,
+}
+
+impl SlackError {
+    /// Whether the token lacks a scope or is the wrong kind of token for the
+    /// method, which retrying won't fix.
+    pub fn is_scope_error(&self) -> bool {
+        matches!(
+            &self.kind,
+            SlackErrorKind::Api(ApiError { error, .. })
+                if error == "missing_scope" || error == "not_allowed_token_type"
+        )
+    }
+
+    /// Scope Slack says the method needs, set for `missing_scope`.
+    pub fn needed_scope(&self) -> Option<&str> {
+        match &self.kind {
+            SlackErrorKind::Api(e) => e.needed.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The `error` code of an `ok: false` response.
+    pub fn api_error(&self) -> Option<&str> {
+        match &self.kind {
+            SlackErrorKind::Api(e) => Some(&e.error),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may well succeed if sent again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.kind, SlackErrorKind::EmptyBody { .. })
+    }
+}
+
+impl From<SlackErrorKind> for SlackError {
+    fn from(kind: SlackErrorKind) -> Self {
+        Self { kind, req_id: None }
+    }
+}
+
+impl std::fmt::Display for SlackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SlackErrorKind::Http(e) => write!(f, "request failed: {}", e)?,
+            SlackErrorKind::Api(ApiError {
+                error,
+                needed: Some(needed),
+                provided,
+            }) => write!(
+                f,
+                "{} (grant the `{}` scope; provided: {})",
+                error,
+                needed,
+                provided.as_deref().unwrap_or("none")
+            )?,
+            SlackErrorKind::Api(e) => write!(f, "{}", e.error)?,
+            SlackErrorKind::Decode(e) => write!(f, "unexpected response: {}", e)?,
+            SlackErrorKind::EmptyBody { status } => write!(f, "empty response (HTTP {})", status)?,
+            SlackErrorKind::InvalidBody {
+                status,
+                snippet,
+                error,
+            } => write!(
+                f,
+                "invalid response (HTTP {}): {}: {:?}",
+                status, error, snippet
+            )?,
+            SlackErrorKind::Redirect { status, location } => write!(
+                f,
+                "unexpected redirect to {} (HTTP {}), possible captive portal",
+                location.as_deref().unwrap_or("an unknown location"),
+                status
+            )?,
+        }
+        match &self.req_id {
+            Some(req_id) => write!(f, " [x-slack-req-id: {}]", req_id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `parse` parameter of `chat.postMessage`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseMode {
+    /// Post the text as is, without linkifying urls or names.
+    None,
+    /// Let Slack linkify urls and `@name`/`#channel` references.
+    Full,
+}
+
+impl std::str::FromStr for ParseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "full" => Ok(Self::Full),
+            _ => Err(format!("unknown parse mode: {}", s)),
+        }
+    }
+}
+
+/// Checks that `name` is an emoji shortcode, `name` or `:name:` made of
+/// alphanumerics, `_`, `+` and `-`, and returns it as `:name:`.
+///
+/// Slack only answers a bad name with an opaque `invalid_name` error.
+pub fn normalize_emoji(name: &str) -> Result<String, String> {
+    let bare = name
+        .strip_prefix(':')
+        .and_then(|n| n.strip_suffix(':'))
+        .unwrap_or(name);
+    if bare.is_empty()
+        || !bare
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'))
+    {
+        return Err(format!("invalid emoji shortcode: {:?}", name));
+    }
+    Ok(format!(":{}:", bare))
+}
+
+#[derive(Serialize, Debug)]
+pub struct PostMessageRequest<'a> {
+    pub channel: &'a str,
+    pub text: &'a str,
+    pub parse: ParseMode,
+    /// Whether Slack formats `*bold*`, `` `code` `` and so on in `text`.
+    pub mrkdwn: bool,
+    /// Emoji used as the icon of the message as `:name:`, see
+    /// [`normalize_emoji`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_emoji: Option<&'a str>,
+}
+
+/// Base url of Slack's Web API.
+pub const SLACK_API_BASE: &str = "https://slack.com/api";
+
+/// Length of the body included in [`SlackErrorKind::InvalidBody`].
+const BODY_SNIPPET_CHARS: usize = 200;
+
+/// Delay before sending a request again after a transient error.
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn snippet(body: &str) -> String {
+    match body.char_indices().nth(BODY_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}…", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+pub struct SlackClient {
+    pub token: String,
+    pub parse: ParseMode,
+    /// How many times a request is sent again after a transient error.
+    pub transient_retries: u32,
+    /// [`SLACK_API_BASE`], except in tests.
+    pub api_base: String,
+}
+
+impl SlackClient {
+    fn api_url(&self, method: &str) -> String {
+        format!("{}/{}", self.api_base, method)
+    }
+
+    /// Sends an authorized Web API request and decodes a successful response,
+    /// retrying transient errors such as empty bodies.
+    ///
+    /// `request` builds the request afresh for every attempt, as a sent
+    /// request can't be reused.
+    async fn api_call<T: DeserializeOwned>(
+        &self,
+        request: impl Fn() -> surf::RequestBuilder,
+    ) -> Result<ApiResponse<T>, SlackError> {
+        let mut retries = self.transient_retries;
+        loop {
+            match self.send(request()).await {
+                Err(e) if e.is_transient() && retries > 0 => {
+                    retries -= 1;
+                    tracing::warn!("Retrying after {}", e);
+                    async_std::task::sleep(RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends a single request, see [`Self::api_call`].
+    ///
+    /// The body is read as text before parsing it, so errors can tell an
+    /// empty or malformed body from an unexpected shape. Errors carry the
+    /// `x-slack-req-id` of the response, if there was one.
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: surf::RequestBuilder,
+    ) -> Result<ApiResponse<T>, SlackError> {
+        let request = request
+            .header(
+                surf::http::headers::AUTHORIZATION,
+                format!("Bearer {}", self.token),
+            )
+            .build();
+        let method = request.url().path().trim_start_matches("/api/").to_string();
+        let mut response = surf::client()
+            .send(request)
+            .await
+            .map_err(|e| SlackError::from(SlackErrorKind::Http(e)))?;
+        let req_id = response
+            .header("x-slack-req-id")
+            .map(|v| v.as_str().to_string());
+        tracing::debug!(
+            "{} responded {} [x-slack-req-id: {}]",
+            method,
+            response.status(),
+            req_id.as_deref().unwrap_or("-")
+        );
+        let oauth_scopes = response
+            .header("x-oauth-scopes")
+            .map(|v| v.as_str().to_string());
+        let error = |kind| SlackError {
+            kind,
+            req_id: req_id.clone(),
+        };
+
+        let status = response.status() as u16;
+        if response.status().is_redirection() {
+            return Err(error(SlackErrorKind::Redirect {
+                status,
+                location: response
+                    .header(surf::http::headers::LOCATION)
+                    .map(|v| v.as_str().to_string()),
+            }));
+        }
+        let body = response
+            .body_string()
+            .await
+            .map_err(|e| error(SlackErrorKind::Http(e)))?;
+        if body.trim().is_empty() {
+            return Err(error(SlackErrorKind::EmptyBody { status }));
+        }
+        let body: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+            error(SlackErrorKind::InvalidBody {
+                status,
+                snippet: snippet(&body),
+                error: e,
+            })
+        })?;
+        if body.get("ok").and_then(|v| v.as_bool()) != Some(true) {
+            return Err(error(match serde_json::from_value(body) {
+                Ok(e) => SlackErrorKind::Api(e),
+                Err(e) => SlackErrorKind::Decode(e),
+            }));
+        }
+        Ok(ApiResponse {
+            body: serde_json::from_value(body).map_err(|e| error(SlackErrorKind::Decode(e)))?,
+            oauth_scopes,
+            status,
+        })
+    }
+
+    /// Calls `method` with `body` as JSON.
+    async fn post_json<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: &impl Serialize,
+    ) -> Result<T, SlackError> {
+        let body = serde_json::to_string(body).map_err(SlackErrorKind::Decode)?;
+        self.api_call(|| {
+            surf::post(self.api_url(method))
+                .header(
+                    surf::http::headers::CONTENT_TYPE,
+                    "application/json; charset=utf-8",
+                )
+                .body_string(body.clone())
+        })
+        .await
+        .map(|response| response.body)
+    }
+
+    /// Asks for the url of a new socket mode connection. The client must
+    /// carry the app-level token.
+    pub async fn open_connections(
+        &self,
+        user_agent: &str,
+    ) -> Result<OpenConnectionsResponse, SlackError> {
+        let start = Instant::now();
+        let response = self
+            .api_call(|| {
+                surf::post(self.api_url("apps.connections.open"))
+                    .header(surf::http::headers::USER_AGENT, user_agent)
+            })
+            .await?;
+        Ok(OpenConnectionsResponse {
+            elapsed: start.elapsed(),
+            status: response.status,
+            ..response.body
+        })
+    }
+
+    pub async fn auth_test(&self) -> Result<AuthTestResponse, SlackError> {
+        let response = self
+            .api_call(|| surf::post(self.api_url("auth.test")))
+            .await?;
+        Ok(AuthTestResponse {
+            scopes: response.oauth_scopes.as_deref().map(parse_scopes),
+            ..response.body
+        })
+    }
+
+    pub async fn users_info(&self, user: &str) -> Result<UsersInfoResponse, SlackError> {
+        self.api_call(|| {
+            surf::get(format!(
+                "{}?user={}&include_locale=true",
+                self.api_url("users.info"),
+                user
+            ))
+        })
+        .await
+        .map(|response| response.body)
+    }
+
+    /// Opens (or reuses) the direct message channel with `user` and returns
+    /// its id.
+    pub async fn open_dm(&self, user: &str) -> Result<String, SlackError> {
+        let response: ConversationsOpenResponse = self
+            .post_json("conversations.open", &serde_json::json!({ "users": user }))
+            .await?;
+        Ok(response.channel.id)
+    }
+
+    /// Joins the public channel `channel`.
+    pub async fn join_channel(&self, channel: &str) -> Result<(), SlackError> {
+        self.post_json::<serde_json::Value>(
+            "conversations.join",
+            &serde_json::json!({ "channel": channel }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// A `chat.postMessage` request with this client's defaults.
+    pub fn message<'a>(&self, channel: &'a str, text: &'a str) -> PostMessageRequest<'a> {
+        PostMessageRequest {
+            channel,
+            text,
+            parse: self.parse,
+            mrkdwn: true,
+            icon_emoji: None,
+        }
+    }
+
+    pub async fn post_message(
+        &self,
+        message: &PostMessageRequest<'_>,
+    ) -> Result<PostMessageResponse, SlackError> {
+        self.post_json("chat.postMessage", message).await
+    }
+
+    pub async fn send_message(&self, channel: &str, text: &str) -> Result<(), SlackError> {
+        self.post_message(&self.message(channel, text)).await?;
+        Ok(())
+    }
+
+    /// Posts a message only `user` can see in `channel`.
+    pub async fn send_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+    ) -> Result<(), SlackError> {
+        self.post_json::<serde_json::Value>(
+            "chat.postEphemeral",
+            &serde_json::json!({
+                "channel": channel,
+                "user": user,
+                "text": text,
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use serde_json::json;
+
+    fn client(parse: ParseMode) -> SlackClient {
+        SlackClient {
+            token: "xoxb-test".to_string(),
+            parse,
+            transient_retries: 0,
+            api_base: SLACK_API_BASE.to_string(),
+        }
+    }
+
+    fn client_of(server: &TestServer) -> SlackClient {
+        SlackClient {
+            api_base: server.api_base(),
+            ..client(ParseMode::None)
+        }
+    }
+
+    #[async_std::test]
+    async fn open_connections_retries_an_empty_body() {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let server = TestServer::start(move |_| {
+            match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Response::new(200, ""),
+                _ => Response::json(json!({"ok": true, "url": "wss://wss-primary.slack.com/link"})),
+            }
+        });
+        let client = SlackClient {
+            transient_retries: 1,
+            ..client_of(&server)
+        };
+        let response = client.open_connections("test").await.unwrap();
+        assert_eq!(
+            response.url.as_deref(),
+            Some("wss://wss-primary.slack.com/link")
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            server.methods(),
+            ["apps.connections.open", "apps.connections.open"]
+        );
+    }
+
+    #[async_std::test]
+    async fn open_connections_measures_the_call_and_collects_warnings() {
+        let server = TestServer::start(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            Response::json(json!({
+                "ok": true,
+                "url": "wss://wss-primary.slack.com/link",
+                "warning": "superfluous_charset,missing_charset",
+                "response_metadata": {"warnings": ["missing_charset", "deprecated"]},
+            }))
+        });
+        let response = client_of(&server).open_connections("test").await.unwrap();
+        assert!(
+            response.elapsed >= Duration::from_millis(50),
+            "{:?}",
+            response.elapsed
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.warnings(),
+            ["superfluous_charset", "missing_charset", "deprecated"]
+        );
+    }
+
+    #[async_std::test]
+    async fn open_connections_reports_invalid_bodies_with_a_snippet() {
+        let server = TestServer::start(|_| Response::new(502, "<html>Bad Gateway</html>"));
+        let error = client_of(&server)
+            .open_connections("test")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error.kind, SlackErrorKind::InvalidBody { status: 502, snippet, .. } if snippet == "<html>Bad Gateway</html>"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[async_std::test]
+    async fn redirects_are_reported_instead_of_followed() {
+        let server = TestServer::start(|request| match request.method() {
+            "apps.connections.open" => {
+                Response::new(302, "").header("location", "http://portal.example/login")
+            }
+            _ => Response::new(200, "<html>Sign in to the network</html>"),
+        });
+        let error = client_of(&server)
+            .open_connections("test")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error.kind, SlackErrorKind::Redirect { status: 302, location: Some(location) } if location == "http://portal.example/login"),
+            "{:?}",
+            error
+        );
+        assert_eq!(server.methods(), ["apps.connections.open"]);
+        assert!(
+            error.to_string().contains("possible captive portal"),
+            "{}",
+            error
+        );
+    }
+
+    #[async_std::test]
+    async fn errors_carry_the_request_id() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({"ok": false, "error": "channel_not_found"}))
+                .header("x-slack-req-id", "req-123")
+        });
+        let error = client_of(&server)
+            .send_message("C1", "hi")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error.kind, SlackErrorKind::Api(e) if e.error == "channel_not_found"),
+            "{:?}",
+            error
+        );
+        assert_eq!(error.req_id.as_deref(), Some("req-123"));
+        assert_eq!(
+            error.to_string(),
+            "channel_not_found [x-slack-req-id: req-123]"
+        );
+    }
+
+    #[test]
+    fn user_agent_carries_the_connection_tag() {
+        let base = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+        assert_eq!(user_agent(None), base);
+        assert_eq!(user_agent(Some("blue")), format!("{} (blue)", base));
+    }
+
+    #[test]
+    fn parses_scopes() {
+        let scopes = parse_scopes("chat:write, users:read,,app_mentions:read ");
+        let mut scopes: Vec<_> = scopes.iter().map(String::as_str).collect();
+        scopes.sort_unstable();
+        assert_eq!(scopes, ["app_mentions:read", "chat:write", "users:read"]);
+        assert!(parse_scopes("").is_empty());
+    }
+
+    #[async_std::test]
+    async fn auth_test_reads_the_scopes_header() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({"ok": true, "user_id": "UBOT", "bot_id": "BBOT"}))
+                .header("x-oauth-scopes", "chat:write,users:read")
+        });
+        let auth = client_of(&server).auth_test().await.unwrap();
+        assert_eq!(auth.user_id.as_deref(), Some("UBOT"));
+        assert_eq!(auth.bot_id.as_deref(), Some("BBOT"));
+        assert_eq!(auth.scopes, Some(parse_scopes("users:read,chat:write")));
+    }
+
+    #[async_std::test]
+    async fn scope_errors_name_the_needed_and_provided_scopes() {
+        let server = TestServer::start(|request| match request.method() {
+            "chat.postMessage" => Response::json(json!({
+                "ok": false,
+                "error": "missing_scope",
+                "needed": "chat:write",
+                "provided": "im:history,users:read",
+            })),
+            "chat.postEphemeral" => {
+                Response::json(json!({"ok": false, "error": "not_allowed_token_type"}))
+            }
+            _ => Response::json(json!({"ok": false, "error": "channel_not_found"})),
+        });
+        let client = client_of(&server);
+        let e = client.send_message("C1", "hi").await.unwrap_err();
+        assert!(e.is_scope_error());
+        assert_eq!(e.needed_scope(), Some("chat:write"));
+        assert_eq!(
+            e.to_string(),
+            "missing_scope (grant the `chat:write` scope; provided: im:history,users:read)"
+        );
+        let e = client.send_ephemeral("C1", "U1", "hi").await.unwrap_err();
+        assert!(e.is_scope_error());
+        assert_eq!(e.needed_scope(), None);
+        assert_eq!(e.to_string(), "not_allowed_token_type");
+        let e = client.open_dm("U1").await.unwrap_err();
+        assert!(!e.is_scope_error());
+    }
+
+    #[test]
+    fn normalizes_emoji_shortcodes() {
+        assert_eq!(normalize_emoji("robot_face").unwrap(), ":robot_face:");
+        assert_eq!(normalize_emoji(":+1:").unwrap(), ":+1:");
+        assert_eq!(normalize_emoji("flag-jp").unwrap(), ":flag-jp:");
+        for invalid in ["", "::", "robot face", ":robot_face", "ロボット", "a:b"] {
+            assert_eq!(
+                normalize_emoji(invalid).unwrap_err(),
+                format!("invalid emoji shortcode: {:?}", invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn icon_emoji_is_left_out_unless_set() {
+        let mut message = client(ParseMode::None).message("C1", "hi");
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(json.get("icon_emoji").is_none());
+        message.icon_emoji = Some(":robot_face:");
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["icon_emoji"], ":robot_face:");
+    }
+
+    #[test]
+    fn serializes_the_parse_mode() {
+        let message = client(ParseMode::None).message("C1", "hi");
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["parse"], "none");
+        let message = client(ParseMode::Full).message("C1", "hi");
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["parse"], "full");
+    }
+}
@@ -0,0 +1,1177 @@
+use crate::app::{App, Outcome};
+use crate::config::RawConfig;
+use crate::echo::truncate_chars;
+use crate::interactive::InteractivePayload;
+use crate::reconnect::Backoff;
+use crate::slack::{user_agent, SlackClient};
+use async_std::stream::StreamExt;
+use futures_util::sink::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+pub type WebSocketStream =
+    async_tungstenite::WebSocketStream<async_tls::client::TlsStream<async_std::net::TcpStream>>;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum SocketModeMessage<'s> {
+    Hello {
+        num_connections: Option<u32>,
+        debug_info: Option<HelloDebugInfo>,
+    },
+    Disconnect {
+        /// Missing in malformed or minimal messages, which still mean
+        /// reconnecting.
+        reason: Option<&'s str>,
+    },
+    EventsApi {
+        envelope_id: &'s str,
+        /// Number of earlier deliveries of this envelope, `0` the first time.
+        #[serde(default)]
+        retry_attempt: u32,
+        retry_reason: Option<&'s str>,
+    },
+    Interactive {
+        envelope_id: &'s str,
+    },
+}
+
+impl SocketModeMessage<'_> {
+    /// Id of envelopes which must be acked.
+    fn envelope_id(&self) -> Option<&str> {
+        match self {
+            Self::EventsApi { envelope_id, .. } | Self::Interactive { envelope_id } => {
+                Some(envelope_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HelloDebugInfo {
+    pub host: Option<String>,
+    pub build_number: Option<u64>,
+    pub approximate_connection_time: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SocketModeAcknowledgeMessage<'s> {
+    pub envelope_id: &'s str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<&'s str>,
+}
+
+/// State of a single socket mode connection.
+#[derive(Default)]
+pub struct ConnectionState {
+    pub hello_count: u32,
+    pub connected_at: Option<std::time::Instant>,
+}
+
+impl ConnectionState {
+    /// Records a `hello` and returns `true` if it is the first one on this
+    /// connection. Slack may repeat `hello`, which must not be treated as a new
+    /// connection.
+    pub fn on_hello(&mut self) -> bool {
+        self.hello_count += 1;
+        if self.connected_at.is_some() {
+            return false;
+        }
+        self.connected_at = Some(std::time::Instant::now());
+        true
+    }
+}
+
+/// Why a connection ended.
+#[derive(Debug)]
+pub enum ConnectionEnd {
+    /// Slack asked us to reconnect with a `disconnect` message.
+    Disconnect(String),
+    /// Reading from or writing to the socket failed.
+    Error(String),
+    /// Slack told us not to reconnect, e.g. because socket mode was disabled
+    /// for the app.
+    Fatal(String),
+    /// The stream ended without a close frame, typically because the TCP
+    /// connection dropped silently.
+    StreamEnded,
+    /// The inspection hook asked to stop.
+    Stopped,
+    /// The connection reached its maximum lifetime and was replaced by this
+    /// one, which is already open.
+    Rotated(Box<WebSocketStream>),
+}
+
+/// What to do with a socket mode message, as decided by
+/// [`LoopConfig::inspect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectDecision {
+    /// Handle the message as usual.
+    Process,
+    /// Ignore the message. Envelopes are still acknowledged.
+    Skip,
+    /// Close the connection and stop the bot.
+    Stop,
+}
+
+type InspectHook = Box<dyn Fn(&SocketModeMessage) -> InspectDecision + Send + Sync>;
+
+/// Hooks into [`run`].
+pub struct LoopConfig {
+    /// Called with every parsed message before it is handled, e.g. for custom
+    /// filtering, sampling or shutdown triggers.
+    pub inspect: InspectHook,
+}
+
+impl LoopConfig {
+    /// Replaces the inspection hook.
+    pub fn with_inspect(
+        mut self,
+        inspect: impl Fn(&SocketModeMessage) -> InspectDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.inspect = Box::new(inspect);
+        self
+    }
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self {
+            inspect: Box::new(|_| InspectDecision::Process),
+        }
+    }
+}
+
+type WebSocketSink = futures_util::stream::SplitSink<WebSocketStream, tungstenite::Message>;
+
+/// Writes frames to the current connection.
+///
+/// Frames are written by a task owning the write half of the connection, fed
+/// through a channel. The sender is swapped when a connection starts and
+/// removed when it ends, so frames always go to the current connection and
+/// are rejected cleanly in between, e.g. from handlers still running while
+/// reconnecting.
+#[derive(Default)]
+pub struct SocketWriter {
+    sender: Mutex<Option<async_std::channel::Sender<tungstenite::Message>>>,
+}
+
+impl SocketWriter {
+    pub async fn send(&self, message: tungstenite::Message) -> Result<(), String> {
+        let sender = self.sender.lock().unwrap().clone().ok_or("not connected")?;
+        sender
+            .send(message)
+            .await
+            .map_err(|_| "connection closed".to_string())
+    }
+
+    /// Routes frames to `sink` from now on. The previous connection's writer
+    /// task ends once its pending frames are written.
+    fn attach(&self, mut sink: WebSocketSink) {
+        let (sender, receiver) = async_std::channel::unbounded();
+        async_std::task::spawn(async move {
+            while let Ok(message) = receiver.recv().await {
+                if let Err(e) = sink.send(message).await {
+                    tracing::warn!("Failed to write frame: {}", e);
+                    break;
+                }
+            }
+        });
+        *self.sender.lock().unwrap() = Some(sender);
+    }
+
+    fn detach(&self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+/// Checks that the url from `apps.connections.open` is a `wss://` url of a
+/// Slack host before connecting to it.
+///
+/// The error is returned like any other connection failure, so a transient
+/// server issue handing out a bad url is retried.
+pub fn validate_wss_url(wss_url: &str) -> Result<url::Url, String> {
+    let url = url::Url::parse(wss_url)
+        .map_err(|e| format!("Malformed websocket url {:?}: {}", wss_url, e))?;
+    if url.scheme() != "wss" {
+        return Err(format!(
+            "Websocket url {:?} has scheme {:?}, expected \"wss\"",
+            wss_url,
+            url.scheme()
+        ));
+    }
+    match url.domain() {
+        Some(domain) if domain == "slack.com" || domain.ends_with(".slack.com") => Ok(url),
+        _ => Err(format!(
+            "Websocket url {:?} is not on a slack.com host",
+            wss_url
+        )),
+    }
+}
+
+/// Opens a new socket mode connection once the handshake gate lets it.
+pub async fn open(app: &App) -> Result<WebSocketStream, String> {
+    let config = app.config();
+    app.handshakes
+        .run(
+            config.handshake_spacing,
+            connect(&config, &app.installation.slack_client.api_base),
+        )
+        .await
+}
+
+/// Opens a new socket mode connection.
+async fn connect(config: &RawConfig, api_base: &str) -> Result<WebSocketStream, String> {
+    let app_client = SlackClient {
+        token: config.app_level_token.clone(),
+        parse: config.message_parse,
+        transient_retries: config.transient_retries,
+        api_base: api_base.to_string(),
+    };
+    let con_result = app_client
+        .open_connections(&user_agent(config.connection_tag.as_deref()))
+        .await
+        .map_err(|e| format!("Failed to request apps.connections.open: {}", e))?;
+    if con_result.elapsed >= config.slow_connections_open {
+        tracing::warn!(
+            "apps.connections.open took {:?} (status {})",
+            con_result.elapsed,
+            con_result.status
+        );
+    } else {
+        tracing::debug!(
+            "apps.connections.open took {:?} (status {})",
+            con_result.elapsed,
+            con_result.status
+        );
+    }
+    for warning in con_result.warnings() {
+        tracing::warn!("apps.connections.open warning: {}", warning);
+    }
+    let wss_url = con_result.url.ok_or("no url passed from server")?;
+    let url = validate_wss_url(&wss_url)?;
+    let domain = url.domain().ok_or("no domain name?")?;
+    let tcp_stream = async_std::net::TcpStream::connect(&format!("{}:443", domain))
+        .await
+        .map_err(|e| format!("Failed to connect tcp stream: {}", e))?;
+    let enc_stream = async_tls::TlsConnector::default()
+        .connect(domain, tcp_stream)
+        .await
+        .map_err(|e| format!("Failed to connect encrypted stream: {}", e))?;
+    let (stream, _) = async_tungstenite::client_async(wss_url, enc_stream)
+        .await
+        .map_err(|e| format!("Failed to connect websocket: {}", e))?;
+    Ok(stream)
+}
+
+/// Acknowledges a single envelope.
+///
+/// Slack expects one ack per envelope within a few seconds of delivery, and
+/// redelivers envelopes which aren't acked in time. So every envelope is acked
+/// on its own, right after it is read and before its event is handed to the
+/// (concurrent) handlers. Acks must never be batched or deferred until
+/// handling is done.
+async fn acknowledge(writer: &SocketWriter, envelope_id: &str) -> Result<(), String> {
+    let ack = serde_json::to_string(&SocketModeAcknowledgeMessage {
+        envelope_id,
+        payload: None,
+    })
+    .expect("Failed to serialize ack message");
+    writer
+        .send(tungstenite::Message::Text(ack))
+        .await
+        .map_err(|e| format!("Failed to reply ack message: {}", e))
+}
+
+/// Logs the one summary line of an event, with structured fields for log
+/// analysis.
+fn log_summary(
+    envelope_id: &str,
+    event_type: Option<&str>,
+    channel: Option<&str>,
+    outcome: &Outcome,
+    duration: Duration,
+) {
+    tracing::info!(
+        envelope_id,
+        event_type,
+        channel,
+        outcome = outcome.as_str(),
+        deduped = *outcome == Outcome::Duplicate,
+        replied = outcome.replied(),
+        ts = outcome.ts(),
+        duration_ms = duration.as_millis() as u64,
+        "Event summary"
+    );
+}
+
+#[derive(Deserialize)]
+struct InteractiveEnvelope {
+    payload: serde_json::Value,
+}
+
+/// Handles an interactive `payload` in the background, traced in an
+/// `interactive` span and ending with a summary line like events.
+fn dispatch_interactive(app: &Arc<App>, envelope_id: &str, payload: serde_json::Value) {
+    let payload_type = payload
+        .get("type")
+        .and_then(|v| v.as_str())
+        .map(str::to_string);
+    let payload = match serde_json::from_value::<InteractivePayload>(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::warn!("Failed to parse interactive payload: {}", e);
+            return;
+        }
+    };
+    let span = tracing::info_span!("interactive", envelope_id, outcome = tracing::field::Empty);
+    let app = Arc::clone(app);
+    let envelope_id = envelope_id.to_string();
+    async_std::task::spawn(
+        async move {
+            let started = Instant::now();
+            let outcome = app.handle_interactive(&payload).await;
+            tracing::Span::current().record("outcome", outcome.as_str());
+            log_summary(
+                &envelope_id,
+                payload_type.as_deref(),
+                None,
+                &outcome,
+                started.elapsed(),
+            );
+        }
+        .instrument(span)
+        .with_current_subscriber(),
+    );
+}
+
+/// Handles `event` in the background.
+///
+/// Events are handled concurrently, except that events of the same channel
+/// are handled one at a time in the order they arrived so replies keep their
+/// order.
+///
+/// Handling is traced in an `event` span, a child of the connection's span,
+/// carrying the envelope id, event type, channel and outcome, and ends with a
+/// summary line, see [`log_summary`].
+fn dispatch_event(
+    app: &Arc<App>,
+    envelope_id: &str,
+    team_id: Option<String>,
+    event: serde_json::Value,
+) {
+    let channel = event
+        .get("channel")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let event_type = event.get("type").and_then(|v| v.as_str());
+    let span = tracing::info_span!(
+        "event",
+        envelope_id,
+        event_type,
+        channel = channel.as_str(),
+        outcome = tracing::field::Empty,
+    );
+    let handler = {
+        let app = Arc::clone(app);
+        let envelope_id = envelope_id.to_string();
+        let channel = channel.clone();
+        async move {
+            let started = Instant::now();
+            let outcome = app.handle_event(&event, team_id.as_deref()).await;
+            tracing::Span::current().record("outcome", outcome.as_str());
+            log_summary(
+                &envelope_id,
+                event.get("type").and_then(|v| v.as_str()),
+                Some(channel.as_str()).filter(|c| !c.is_empty()),
+                &outcome,
+                started.elapsed(),
+            );
+        }
+        .instrument(span)
+        .with_current_subscriber()
+    };
+    app.channel_queue.spawn(channel, handler);
+}
+
+/// Delay before trying again when opening the next connection for a rotation
+/// failed.
+const ROTATION_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Incoming frames of a connection.
+trait FrameStream:
+    futures_util::Stream<Item = Result<tungstenite::Message, tungstenite::Error>>
+{
+}
+
+impl<S> FrameStream for S where
+    S: futures_util::Stream<Item = Result<tungstenite::Message, tungstenite::Error>>
+{
+}
+
+/// What [`read_frames`] waits for.
+enum Input {
+    Frame(Option<Result<tungstenite::Message, tungstenite::Error>>),
+    /// The connection reached its maximum lifetime.
+    Expired,
+    /// An admin asked to reconnect.
+    ReconnectRequested,
+}
+
+/// Waits for the next frame or a reconnect request.
+async fn next_input(
+    stream: &mut (impl FrameStream + Unpin),
+    reconnect_requests: &async_std::channel::Receiver<()>,
+) -> Input {
+    let reconnect_requested = Box::pin(reconnect_requests.recv());
+    match futures_util::future::select(stream.next(), reconnect_requested).await {
+        futures_util::future::Either::Left((frame, _)) => Input::Frame(frame),
+        futures_util::future::Either::Right(_) => Input::ReconnectRequested,
+    }
+}
+
+/// Replaces the current connection once it reached its maximum lifetime or
+/// an admin asked to reconnect.
+///
+/// The next connection is opened before the current one is closed (make
+/// before break) so there is always a connection for Slack to deliver to.
+/// Envelopes which were delivered to the old connection but not acked yet are
+/// redelivered by Slack.
+async fn rotate(app: &Arc<App>) -> Result<WebSocketStream, String> {
+    let new_stream = open(app).await?;
+    if let Err(e) = app
+        .socket_writer
+        .send(tungstenite::Message::Close(None))
+        .await
+    {
+        tracing::debug!("Failed to close the old connection: {}", e);
+    }
+    Ok(new_stream)
+}
+
+/// Handles frames of a connection until it ends.
+///
+/// Frames are written through [`App::socket_writer`], which is pointed at this
+/// connection meanwhile. The backoff is reset on the first `hello`, i.e. once
+/// the connection is known to be usable.
+pub async fn run(
+    app: &Arc<App>,
+    loop_config: &LoopConfig,
+    stream: WebSocketStream,
+    backoff: &mut Backoff,
+) -> ConnectionEnd {
+    let (sink, stream) = futures_util::StreamExt::split(stream);
+    app.socket_writer.attach(sink);
+    let end = read_frames(app, loop_config, stream, backoff).await;
+    app.socket_writer.detach();
+    end
+}
+
+async fn read_frames(
+    app: &Arc<App>,
+    loop_config: &LoopConfig,
+    mut stream: impl FrameStream + Unpin,
+    backoff: &mut Backoff,
+) -> ConnectionEnd {
+    let mut connection = ConnectionState::default();
+    let mut rotate_at = app
+        .config()
+        .max_connection_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    loop {
+        let input = next_input(&mut stream, &app.reconnect_requests.1);
+        let input = match rotate_at {
+            Some(at) => {
+                let remaining = at.saturating_duration_since(Instant::now());
+                async_std::future::timeout(remaining, input)
+                    .await
+                    .unwrap_or(Input::Expired)
+            }
+            None => input.await,
+        };
+        let next = match input {
+            Input::Frame(next) => next,
+            Input::Expired | Input::ReconnectRequested => {
+                match input {
+                    Input::Expired => {
+                        tracing::info!("Connection reached its maximum lifetime, rotating")
+                    }
+                    _ => tracing::info!("Reconnect requested, rotating"),
+                }
+                match rotate(app).await {
+                    Ok(new_stream) => return ConnectionEnd::Rotated(Box::new(new_stream)),
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to rotate the connection, keeping it for now: {}",
+                            e
+                        );
+                        rotate_at = Some(Instant::now() + ROTATION_RETRY_DELAY);
+                        continue;
+                    }
+                }
+            }
+        };
+        let m = match next {
+            Some(m) => m,
+            None => break,
+        };
+        let m = match m {
+            Ok(m) => m,
+            Err(e) => {
+                return ConnectionEnd::Error(format!("Failed to decode websocket frame: {}", e))
+            }
+        };
+        let config = app.config();
+        app.frame_sizes.record(m.len());
+        if m.len() >= config.large_frame_bytes
+            && rand::random::<f64>() < config.large_frame_log_sample_rate
+        {
+            tracing::info!("Large frame: {} bytes", m.len());
+        }
+        match m {
+            tungstenite::Message::Text(t) => {
+                let message = match serde_json::from_str::<SocketModeMessage>(&t) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        tracing::warn!("Unknown text frame: {}: {:?}", t, e);
+                        continue;
+                    }
+                };
+                // Envelopes are acked whatever the inspection hook decides so
+                // Slack doesn't redeliver them.
+                if let Some(envelope_id) = message.envelope_id() {
+                    // Slack's envelope ids are UUIDs; anything much longer is
+                    // malformed and not echoed back in an ack.
+                    if envelope_id.len() > config.max_envelope_id_length {
+                        tracing::warn!(
+                            "Skipping envelope with an id of {} bytes: {:?}…",
+                            envelope_id.len(),
+                            truncate_chars(envelope_id, 16)
+                        );
+                        continue;
+                    }
+                    if let Err(e) = acknowledge(&app.socket_writer, envelope_id).await {
+                        return ConnectionEnd::Error(e);
+                    }
+                }
+                match (loop_config.inspect)(&message) {
+                    InspectDecision::Process => {}
+                    InspectDecision::Skip => {
+                        tracing::debug!("Inspection hook skipped message: {}", t);
+                        continue;
+                    }
+                    InspectDecision::Stop => {
+                        tracing::info!("Inspection hook stopped the bot at message: {}", t);
+                        return ConnectionEnd::Stopped;
+                    }
+                }
+
+                match message {
+                    SocketModeMessage::Hello {
+                        num_connections,
+                        debug_info,
+                    } => {
+                        if connection.on_hello() {
+                            backoff.reset();
+                            app.start_warmup();
+                            let debug_info = debug_info.unwrap_or_default();
+                            tracing::info!(
+                                "Hello: tag={:?} num_connections={:?} host={:?} build_number={:?} approximate_connection_time={:?}",
+                                config.connection_tag,
+                                num_connections,
+                                debug_info.host,
+                                debug_info.build_number,
+                                debug_info.approximate_connection_time
+                            );
+                        } else {
+                            tracing::info!(
+                                "Duplicate hello #{} (connected {:?} ago): num_connections={:?}",
+                                connection.hello_count,
+                                connection
+                                    .connected_at
+                                    .map(|t| t.elapsed())
+                                    .unwrap_or_default(),
+                                num_connections
+                            );
+                        }
+                    }
+                    SocketModeMessage::Disconnect { reason, .. } => {
+                        let reason = reason.unwrap_or("unknown");
+                        if config.fatal_disconnect_reasons.contains(reason) {
+                            tracing::error!("Fatal disconnect request: {}", reason);
+                            return ConnectionEnd::Fatal(format!("disconnect: {}", reason));
+                        }
+                        tracing::info!("Disconnect request: {}", reason);
+                        return ConnectionEnd::Disconnect(reason.to_string());
+                    }
+                    SocketModeMessage::EventsApi {
+                        envelope_id,
+                        retry_attempt,
+                        retry_reason,
+                        ..
+                    } => {
+                        tracing::debug!("Events API Message: {}", t);
+                        match serde_json::from_str::<serde_json::Value>(&t) {
+                            Ok(mut v) => {
+                                let event_id = v
+                                    .get("payload")
+                                    .and_then(|v| v.get("event_id"))
+                                    .and_then(|v| v.as_str());
+                                if !app.first_delivery(event_id, retry_attempt, retry_reason) {
+                                    let event = &v["payload"]["event"];
+                                    log_summary(
+                                        envelope_id,
+                                        event.get("type").and_then(|v| v.as_str()),
+                                        event.get("channel").and_then(|v| v.as_str()),
+                                        &Outcome::Duplicate,
+                                        Duration::ZERO,
+                                    );
+                                    continue;
+                                }
+                                let team_id = app.authorized_team(&v["payload"]);
+                                match v.get_mut("payload").and_then(|v| v.get_mut("event")) {
+                                    Some(event) => {
+                                        dispatch_event(app, envelope_id, team_id, event.take())
+                                    }
+                                    None => {
+                                        tracing::warn!("Events API message without an event: {}", t)
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::warn!("Failed to parse event: {}", e);
+                            }
+                        }
+                    }
+                    SocketModeMessage::Interactive { envelope_id } => {
+                        tracing::debug!("Interactive message: {}", t);
+                        match serde_json::from_str::<InteractiveEnvelope>(&t) {
+                            Ok(envelope) => {
+                                dispatch_interactive(app, envelope_id, envelope.payload)
+                            }
+                            Err(e) => tracing::warn!("Failed to parse interactive envelope: {}", e),
+                        }
+                    }
+                }
+            }
+            tungstenite::Message::Ping(bytes) => {
+                tracing::debug!("ping: {:?}", bytes);
+            }
+            tungstenite::Message::Close(frame) => {
+                let (code, reason) = frame
+                    .map(|f| (f.code, f.reason.into_owned()))
+                    .unwrap_or((CloseCode::Status, String::new()));
+                let description = format!("close frame: {} {:?}", code, reason);
+                // Slack closes with a policy violation when the app token was
+                // revoked or lost access, which reconnecting won't fix. Other
+                // codes, such as 1001 when a server goes away for a restart,
+                // are answered by reconnecting.
+                if code == CloseCode::Policy {
+                    tracing::error!("Fatal {}", description);
+                    return ConnectionEnd::Fatal(description);
+                }
+                tracing::info!("Received {}", description);
+                return ConnectionEnd::Disconnect(description);
+            }
+            _ => tracing::debug!("Unknown frame"),
+        }
+    }
+    ConnectionEnd::StreamEnded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use serde_json::json;
+
+    fn events_api_frame(envelope_id: &str, channel: &str) -> tungstenite::Message {
+        tungstenite::Message::Text(
+            json!({
+                "type": "events_api",
+                "envelope_id": envelope_id,
+                "payload": {
+                    "event_id": format!("Ev{}", envelope_id),
+                    "event": {"type": "reaction_added", "channel": channel, "reaction": "+1"},
+                },
+            })
+            .to_string(),
+        )
+    }
+
+    /// Points `writer` at a new in-memory connection.
+    fn attach_channel(writer: &SocketWriter) -> async_std::channel::Receiver<tungstenite::Message> {
+        let (sender, frames) = async_std::channel::unbounded();
+        *writer.sender.lock().unwrap() = Some(sender);
+        frames
+    }
+
+    fn texts(frames: &async_std::channel::Receiver<tungstenite::Message>) -> Vec<String> {
+        std::iter::from_fn(|| frames.try_recv().ok())
+            .map(|frame| frame.into_text().unwrap())
+            .collect()
+    }
+
+    /// Log output shared with the test reading it.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The lines `log` writes at info.
+    fn logged(log: impl FnOnce()) -> String {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            })
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        tracing::subscriber::with_default(subscriber, log);
+        let bytes = captured.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[async_std::test]
+    async fn summarizes_an_echoed_mention() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({"ok": true, "channel": "C1", "ts": "1234.5678"}))
+        });
+        let app = App::for_test_with_api(RawConfig::for_test(&[]).unwrap(), &server.api_base());
+        let mention = json!({"type": "app_mention", "channel": "C1", "user": "U1", "text": "<@UBOT> hi", "ts": "2.0"});
+        let outcome = app.handle_event(&mention, None).await;
+        let line = logged(|| {
+            log_summary(
+                "E1",
+                Some("app_mention"),
+                Some("C1"),
+                &outcome,
+                Duration::from_millis(12),
+            )
+        });
+        assert!(line.contains(" INFO "), "{}", line);
+        for field in [
+            "Event summary",
+            "envelope_id=\"E1\"",
+            "event_type=\"app_mention\"",
+            "channel=\"C1\"",
+            "outcome=\"echoed\"",
+            "deduped=false",
+            "replied=true",
+            "ts=\"1234.5678\"",
+            "duration_ms=12",
+        ] {
+            assert!(line.contains(field), "{} missing from {}", field, line);
+        }
+    }
+
+    /// Lines of `log` for the envelope `envelope_id`.
+    fn lines_of<'l>(log: &'l str, envelope_id: &str) -> Vec<&'l str> {
+        let id = format!("envelope_id=\"{}\"", envelope_id);
+        log.lines()
+            .filter(|line| line.contains("Event summary") && line.contains(&id))
+            .collect()
+    }
+
+    #[test]
+    fn dispatched_events_are_summarized_once() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({"ok": true, "channel": "C1", "ts": "1234.5678"}))
+        });
+        let app = App::for_test_with_api(RawConfig::for_test(&[]).unwrap(), &server.api_base());
+        let mention = json!({"type": "app_mention", "channel": "C1", "user": "U1", "text": "<@UBOT> hi", "ts": "2.0"});
+        let message = json!({"type": "message", "channel_type": "channel", "channel": "C2", "user": "U1", "text": "hi", "ts": "3.0"});
+        let view_closed =
+            json!({"type": "view_closed", "user": {"id": "U1"}, "view": {"id": "V1"}});
+        let log = logged(|| {
+            dispatch_event(&app, "E1", None, mention);
+            dispatch_event(&app, "E2", None, message);
+            dispatch_interactive(&app, "E3", view_closed);
+            async_std::task::block_on(async_std::task::sleep(Duration::from_millis(300)));
+        });
+        assert_eq!(log.matches("Event summary").count(), 3, "{}", log);
+        let expected = [
+            (
+                "E1",
+                &[
+                    "event_type=\"app_mention\"",
+                    "channel=\"C1\"",
+                    "outcome=\"echoed\"",
+                    "ts=\"1234.5678\"",
+                ][..],
+            ),
+            (
+                "E2",
+                &[
+                    "event_type=\"message\"",
+                    "channel=\"C2\"",
+                    "outcome=\"ignored\"",
+                ][..],
+            ),
+            (
+                "E3",
+                &["event_type=\"view_closed\"", "outcome=\"handled\""][..],
+            ),
+        ];
+        for (envelope_id, fields) in expected {
+            let lines = lines_of(&log, envelope_id);
+            assert_eq!(lines.len(), 1, "{}", log);
+            for field in fields {
+                assert!(
+                    lines[0].contains(field),
+                    "{} missing from {}",
+                    field,
+                    lines[0]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn summarizes_a_redelivery() {
+        let line = logged(|| {
+            log_summary(
+                "E2",
+                Some("app_mention"),
+                None,
+                &Outcome::Duplicate,
+                Duration::ZERO,
+            )
+        });
+        for field in ["outcome=\"duplicate\"", "deduped=true", "replied=false"] {
+            assert!(line.contains(field), "{} missing from {}", field, line);
+        }
+        assert!(!line.contains("ts="), "{}", line);
+        assert!(!line.contains("channel="), "{}", line);
+    }
+
+    #[async_std::test]
+    async fn frames_go_to_the_current_connection() {
+        let writer = SocketWriter::default();
+        let text = |t: &str| tungstenite::Message::Text(t.to_string());
+        assert_eq!(
+            writer.send(text("early")).await.unwrap_err(),
+            "not connected"
+        );
+        let old = attach_channel(&writer);
+        writer.send(text("a")).await.unwrap();
+        writer.detach();
+        assert_eq!(
+            writer.send(text("between")).await.unwrap_err(),
+            "not connected"
+        );
+        let new = attach_channel(&writer);
+        writer.send(text("b")).await.unwrap();
+        assert_eq!(texts(&old), ["a"]);
+        assert_eq!(texts(&new), ["b"]);
+    }
+
+    #[async_std::test]
+    async fn echoes_are_posted_while_reconnecting() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({"ok": true, "channel": "D1", "ts": "1234.5678"}))
+        });
+        let app = App::for_test_with_api(RawConfig::for_test(&[]).unwrap(), &server.api_base());
+        let frames = attach_channel(&app.socket_writer);
+        app.socket_writer.detach();
+        let message = json!({
+            "type": "message",
+            "channel": "D1",
+            "channel_type": "im",
+            "user": "U1",
+            "text": "hi",
+            "ts": "1.0",
+        });
+        assert_eq!(
+            app.handle_event(&message, None).await,
+            Outcome::Echoed {
+                ts: "1234.5678".to_string()
+            }
+        );
+        // Echoes are posted over HTTP, nothing goes to the socket.
+        assert_eq!(server.methods(), ["chat.postMessage"]);
+        assert!(texts(&frames).is_empty());
+    }
+
+    async fn end_of(frames: Vec<tungstenite::Message>, backoff: &mut Backoff) -> ConnectionEnd {
+        let app = App::for_test(RawConfig::for_test(&[]).unwrap());
+        let _acks = attach_channel(&app.socket_writer);
+        let frames = futures_util::stream::iter(frames.into_iter().map(Ok));
+        read_frames(&app, &LoopConfig::default(), frames, backoff).await
+    }
+
+    #[test]
+    fn parses_a_disconnect_without_a_reason() {
+        let message: SocketModeMessage = serde_json::from_str(r#"{"type":"disconnect"}"#).unwrap();
+        assert!(matches!(
+            message,
+            SocketModeMessage::Disconnect { reason: None }
+        ));
+    }
+
+    #[async_std::test]
+    async fn disconnects_reconnect_unless_fatal() {
+        let disconnect =
+            |message: serde_json::Value| tungstenite::Message::Text(message.to_string());
+        let end = end_of(
+            vec![disconnect(json!({"type": "disconnect"}))],
+            &mut Backoff::default(),
+        )
+        .await;
+        assert!(matches!(end, ConnectionEnd::Disconnect(reason) if reason == "unknown"));
+        let end = end_of(
+            vec![disconnect(
+                json!({"type": "disconnect", "reason": "refresh_requested"}),
+            )],
+            &mut Backoff::default(),
+        )
+        .await;
+        assert!(matches!(end, ConnectionEnd::Disconnect(reason) if reason == "refresh_requested"));
+        let end = end_of(
+            vec![disconnect(
+                json!({"type": "disconnect", "reason": "link_disabled"}),
+            )],
+            &mut Backoff::default(),
+        )
+        .await;
+        assert!(matches!(end, ConnectionEnd::Fatal(_)));
+    }
+
+    #[async_std::test]
+    async fn stream_ending_without_a_close_frame_is_told_apart() {
+        let hello = tungstenite::Message::Text(json!({"type": "hello"}).to_string());
+        let ping = tungstenite::Message::Ping(vec![1]);
+        let mut backoff = Backoff::default();
+        backoff.failed();
+        let end = end_of(vec![hello.clone(), ping.clone()], &mut backoff).await;
+        assert!(matches!(end, ConnectionEnd::StreamEnded));
+        // The hello reset the backoff, so `serve` counts the ending as the
+        // first failure and reconnects after the shortest delay.
+        assert_eq!(backoff.delay(), Duration::ZERO);
+
+        let close = tungstenite::Message::Close(None);
+        let end = end_of(vec![hello, ping, close], &mut backoff).await;
+        assert!(matches!(end, ConnectionEnd::Disconnect(_)));
+    }
+
+    #[async_std::test]
+    async fn close_frames_reconnect_unless_for_a_policy_violation() {
+        let close = |code: u16| {
+            tungstenite::Message::Close(Some(tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(code),
+                reason: "bye".into(),
+            }))
+        };
+        let end = end_of(vec![close(1008)], &mut Backoff::default()).await;
+        assert!(
+            matches!(end, ConnectionEnd::Fatal(reason) if reason == r#"close frame: 1008 "bye""#)
+        );
+        for code in [1000, 1001] {
+            let end = end_of(vec![close(code)], &mut Backoff::default()).await;
+            let expected = format!("close frame: {} \"bye\"", code);
+            assert!(matches!(end, ConnectionEnd::Disconnect(reason) if reason == expected));
+        }
+    }
+
+    #[async_std::test]
+    async fn connections_are_opened_with_the_tagged_user_agent() {
+        let server =
+            TestServer::start(|_| Response::json(json!({"ok": false, "error": "invalid_auth"})));
+        let config = RawConfig::for_test(&[("SLACK_CONNECTION_TAG", "blue")]).unwrap();
+        assert!(connect(&config, &server.api_base()).await.is_err());
+        let requests = server.requests();
+        assert_eq!(requests[0].method(), "apps.connections.open");
+        assert_eq!(
+            requests[0].header("user-agent"),
+            Some(user_agent(Some("blue")).as_str())
+        );
+    }
+
+    #[async_std::test]
+    async fn connection_past_its_lifetime_is_rotated() {
+        let server =
+            TestServer::start(|_| Response::json(json!({"ok": false, "error": "invalid_auth"})));
+        let mut config = RawConfig::for_test(&[]).unwrap();
+        config.max_connection_lifetime = Some(Duration::from_millis(50));
+        let app = App::for_test_with_api(config, &server.api_base());
+        let _frames = attach_channel(&app.socket_writer);
+        // Quiet until well past the lifetime, then dropped.
+        let frames = Box::pin(futures_util::stream::once(async {
+            async_std::task::sleep(Duration::from_millis(300)).await;
+            Ok(tungstenite::Message::Ping(vec![]))
+        }));
+        let end = read_frames(
+            &app,
+            &LoopConfig::default(),
+            frames,
+            &mut Backoff::default(),
+        )
+        .await;
+        // Opening the next connection failed, so the old one was kept.
+        assert!(matches!(end, ConnectionEnd::StreamEnded));
+        assert_eq!(server.methods(), ["apps.connections.open"]);
+    }
+
+    #[async_std::test]
+    async fn envelopes_with_over_long_ids_are_skipped() {
+        let app =
+            App::for_test(RawConfig::for_test(&[("SLACK_MAX_ENVELOPE_ID_LENGTH", "8")]).unwrap());
+        let acks = attach_channel(&app.socket_writer);
+        let frames = vec![
+            events_api_frame("12345678", "C1"),
+            events_api_frame("123456789", "C1"),
+        ];
+        let end = read_frames(
+            &app,
+            &LoopConfig::default(),
+            futures_util::stream::iter(frames.into_iter().map(Ok)),
+            &mut Backoff::default(),
+        )
+        .await;
+        assert!(matches!(end, ConnectionEnd::StreamEnded));
+        let acks = texts(&acks);
+        assert_eq!(acks.len(), 1);
+        assert!(
+            acks[0].contains(r#""envelope_id":"12345678""#),
+            "{}",
+            acks[0]
+        );
+        // Only the acked envelope's event was handed on.
+        assert!(app.first_delivery(Some("Ev123456789"), 0, None));
+        assert!(!app.first_delivery(Some("Ev12345678"), 0, None));
+    }
+
+    #[async_std::test]
+    async fn acks_every_envelope_once_in_order() {
+        let app = App::for_test(RawConfig::for_test(&[]).unwrap());
+        let acks = attach_channel(&app.socket_writer);
+        let envelope_ids: Vec<String> = (0..20).map(|i| format!("envelope-{}", i)).collect();
+        // Spread over channels so that the handlers run concurrently.
+        let frames: Vec<_> = envelope_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| events_api_frame(id, &format!("C{}", i % 4)))
+            .collect();
+        let end = read_frames(
+            &app,
+            &LoopConfig::default(),
+            futures_util::stream::iter(frames.into_iter().map(Ok)),
+            &mut Backoff::default(),
+        )
+        .await;
+        assert!(matches!(end, ConnectionEnd::StreamEnded));
+
+        let acked: Vec<_> = texts(&acks)
+            .iter()
+            .map(|ack| {
+                serde_json::from_str::<serde_json::Value>(ack).unwrap()["envelope_id"].clone()
+            })
+            .collect();
+        assert_eq!(acked, envelope_ids);
+    }
+
+    #[test]
+    fn custom_inspect_hook_replaces_the_default() {
+        let hello = SocketModeMessage::Hello {
+            num_connections: None,
+            debug_info: None,
+        };
+        assert_eq!(
+            (LoopConfig::default().inspect)(&hello),
+            InspectDecision::Process
+        );
+        let stop_on_disconnect = LoopConfig::default().with_inspect(|message| match message {
+            SocketModeMessage::Disconnect { .. } => InspectDecision::Stop,
+            _ => InspectDecision::Skip,
+        });
+        assert_eq!((stop_on_disconnect.inspect)(&hello), InspectDecision::Skip);
+        let disconnect = SocketModeMessage::Disconnect {
+            reason: Some("warning"),
+        };
+        assert_eq!(
+            (stop_on_disconnect.inspect)(&disconnect),
+            InspectDecision::Stop
+        );
+    }
+
+    #[test]
+    fn accepts_slack_wss_urls() {
+        assert!(validate_wss_url("wss://wss-primary.slack.com/link/?ticket=1").is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_wss_urls() {
+        let garbage = validate_wss_url("not a url at all").unwrap_err();
+        assert!(
+            garbage.starts_with("Malformed websocket url"),
+            "{}",
+            garbage
+        );
+        let http = validate_wss_url("https://wss-primary.slack.com/link/").unwrap_err();
+        assert!(http.contains("expected \"wss\""), "{}", http);
+        let host = validate_wss_url("wss://slack.com.example.org/link/").unwrap_err();
+        assert!(host.contains("not on a slack.com host"), "{}", host);
+    }
+
+    #[test]
+    fn repeated_hello_starts_the_warmup_and_resets_the_backoff_once() {
+        let app = App::for_test(RawConfig::for_test(&[("SLACK_WARMUP_MS", "60000")]).unwrap());
+        let _acks = attach_channel(&app.socket_writer);
+        let hello = || tungstenite::Message::Text(json!({"type": "hello"}).to_string());
+        // The second hello comes well after the first.
+        let frames = futures_util::StreamExt::chain(
+            futures_util::stream::once(async move { Ok(hello()) }),
+            futures_util::stream::once(async move {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                Ok(hello())
+            }),
+        );
+        let mut backoff = Backoff::default();
+        backoff.failed();
+        let started = Instant::now();
+        let log = logged(|| {
+            let end = async_std::task::block_on(read_frames(
+                &app,
+                &LoopConfig::default(),
+                Box::pin(frames),
+                &mut backoff,
+            ));
+            assert!(matches!(end, ConnectionEnd::StreamEnded));
+        });
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        // The backoff is reset and the warm-up started along with this line.
+        assert_eq!(log.matches("Hello: ").count(), 1, "{}", log);
+        assert_eq!(log.matches("Duplicate hello #2").count(), 1, "{}", log);
+        // The warm-up runs from the first hello, not the second.
+        let first_warmup_end = started + Duration::from_millis(60050);
+        assert_eq!(app.warmup_remaining(first_warmup_end), None);
+        assert!(app.warmup_remaining(started).is_some());
+    }
+
+    #[test]
+    fn second_hello_is_not_a_new_connection() {
+        let mut connection = ConnectionState::default();
+        assert!(connection.on_hello());
+        let connected_at = connection.connected_at;
+        assert!(!connection.on_hello());
+        assert_eq!(connection.hello_count, 2);
+        assert_eq!(connection.connected_at, connected_at);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Number of buckets of [`FrameSizeHistogram`]; the last one is open ended.
+const FRAME_SIZE_BUCKETS: usize = 24;
+
+/// Histogram of incoming frame sizes in power-of-two byte buckets.
+pub struct FrameSizeHistogram {
+    buckets: [AtomicU64; FRAME_SIZE_BUCKETS],
+}
+
+impl Default for FrameSizeHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl FrameSizeHistogram {
+    pub fn record(&self, len: usize) {
+        let bucket = ((usize::BITS - len.leading_zeros()) as usize).min(FRAME_SIZE_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Non-empty buckets as `<upper bound in bytes>:<count>`.
+    pub fn summary(&self) -> String {
+        let buckets: Vec<String> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(i, count)| {
+                let count = count.load(Ordering::Relaxed);
+                if count == 0 {
+                    return None;
+                }
+                Some(if i == FRAME_SIZE_BUCKETS - 1 {
+                    format!(">={}B:{}", 1u64 << (i - 1), count)
+                } else {
+                    format!("<{}B:{}", 1u64 << i, count)
+                })
+            })
+            .collect();
+        if buckets.is_empty() {
+            "none".to_string()
+        } else {
+            buckets.join(", ")
+        }
+    }
+}
+
+/// Number of handled Events API events by inner event type.
+#[derive(Default)]
+pub struct EventTypeCounts {
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl EventTypeCounts {
+    pub fn record(&self, event_type: &str) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(event_type.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts as `<type>=<count>`.
+    pub fn summary(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        if counts.is_empty() {
+            return "none".to_string();
+        }
+        counts
+            .iter()
+            .map(|(event_type, count)| format!("{}={}", event_type, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_sizes_are_bucketed_by_powers_of_two() {
+        let histogram = FrameSizeHistogram::default();
+        assert_eq!(histogram.summary(), "none");
+        for len in [0, 1023, 1024, 2047, 1 << 22, usize::MAX] {
+            histogram.record(len);
+        }
+        assert_eq!(
+            histogram.summary(),
+            "<1B:1, <1024B:1, <2048B:2, >=4194304B:2"
+        );
+        // Just below the open ended bucket.
+        histogram.record((1 << 22) - 1);
+        assert!(
+            histogram.summary().contains("<4194304B:1, >=4194304B:2"),
+            "{}",
+            histogram.summary()
+        );
+    }
+
+    #[test]
+    fn event_types_are_counted_in_order() {
+        let counts = EventTypeCounts::default();
+        assert_eq!(counts.summary(), "none");
+        for event_type in ["message.im", "app_mention", "message.im"] {
+            counts.record(event_type);
+        }
+        assert_eq!(counts.summary(), "app_mention=1, message.im=2");
+    }
+}
@@ -0,0 +1,57 @@
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Installed tracing pipeline, see [`init`].
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Exports the spans which are still buffered.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Failed to shut down the trace exporter: {}", e);
+        }
+    }
+}
+
+/// Sets up logging to stderr, filtered by `RUST_LOG` (default: `info`).
+///
+/// With the `otel` feature, spans are also exported over OTLP/HTTP, configured
+/// with the standard `OTEL_*` variables such as `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// and `OTEL_SERVICE_NAME`.
+pub fn init() -> Telemetry {
+    let registry = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .with(tracing_subscriber::fmt::layer());
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .expect("Failed to build the OTLP exporter");
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .build();
+        registry
+            .with(
+                tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))),
+            )
+            .init();
+        Telemetry { provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        Telemetry {}
+    }
+}
@@ -0,0 +1,154 @@
+//! A local HTTP server standing in for the Web API in tests.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// A request received by a [`TestServer`].
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// Path without the query, e.g. `/api/chat.postMessage`.
+    pub path: String,
+    /// Headers with lowercase names.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// The Web API method called, e.g. `chat.postMessage`.
+    pub fn method(&self) -> &str {
+        self.path.trim_start_matches("/api/")
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+}
+
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn json(body: serde_json::Value) -> Self {
+        Self::new(200, body.to_string()).header("content-type", "application/json")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Answers every request with `respond` on its own thread, recording the
+/// requests in the order they arrived.
+pub struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub fn start(respond: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the test server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+        {
+            let requests = Arc::clone(&requests);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let requests = Arc::clone(&requests);
+                    let respond = Arc::clone(&respond);
+                    std::thread::spawn(move || {
+                        if let Some(request) = read_request(&stream) {
+                            requests.lock().unwrap().push(request.clone());
+                            write_response(stream, respond(&request));
+                        }
+                    });
+                }
+            });
+        }
+        Self { url, requests }
+    }
+
+    /// Base url of the Web API to point a `SlackClient` at.
+    pub fn api_base(&self) -> String {
+        format!("{}/api", self.url)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Methods of the requests received so far.
+    pub fn methods(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|request| request.method().to_string())
+            .collect()
+    }
+}
+
+fn read_request(mut stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let target = line.split_whitespace().nth(1)?.to_string();
+    let mut content_length = 0;
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        let (name, value) = (name.to_ascii_lowercase(), value.trim().to_string());
+        match name.as_str() {
+            "content-length" => content_length = value.parse().ok()?,
+            "expect" => stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").ok()?,
+            _ => {}
+        }
+        headers.push((name, value));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    Some(Request {
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn write_response(mut stream: TcpStream, response: Response) {
+    let mut head = format!(
+        "HTTP/1.1 {} Test\r\ncontent-length: {}\r\nconnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(response.body.as_bytes());
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Leaky bucket limiting how often something happens overall.
+///
+/// Every accepted call adds one to the bucket, which drains continuously at
+/// `per_minute / 60` per second. Calls are refused while the bucket is full,
+/// so at most `per_minute` calls are accepted in a burst and on average no
+/// more than `per_minute` a minute after that.
+pub struct LeakyBucket {
+    capacity: f64,
+    drain_per_sec: f64,
+    /// Level of the bucket and when it was last updated.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl LeakyBucket {
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            drain_per_sec: per_minute as f64 / 60.0,
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Returns `true` and fills the bucket by one if it isn't full.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (level, updated_at) = &mut *state;
+        let now = Instant::now();
+        *level =
+            (*level - now.duration_since(*updated_at).as_secs_f64() * self.drain_per_sec).max(0.0);
+        *updated_at = now;
+        if *level + 1.0 > self.capacity {
+            return false;
+        }
+        *level += 1.0;
+        true
+    }
+}
+
+/// Minimum interval between calls per key, e.g. per channel.
+#[derive(Default)]
+pub struct Cooldowns {
+    last: Mutex<HashMap<String, Instant>>,
+}
+
+impl Cooldowns {
+    /// Returns `true` and starts the cooldown of `key` unless it is cooling
+    /// down from a call less than `interval` ago.
+    pub fn try_acquire(&self, key: &str, interval: Duration) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        last.retain(|_, at| now.duration_since(*at) < interval);
+        if last.contains_key(key) {
+            return false;
+        }
+        last.insert(key.to_string(), now);
+        true
+    }
+
+    /// Ends the cooldown of `key`, e.g. when the call it was started for
+    /// didn't happen after all.
+    pub fn release(&self, key: &str) {
+        self.last.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldowns_are_kept_per_key() {
+        let cooldowns = Cooldowns::default();
+        let interval = Duration::from_secs(60);
+        assert!(cooldowns.try_acquire("C1/1.0", interval));
+        assert!(cooldowns.try_acquire("C1/2.0", interval));
+        assert!(!cooldowns.try_acquire("C1/1.0", interval));
+        assert!(!cooldowns.try_acquire("C1/2.0", interval));
+        cooldowns.release("C1/1.0");
+        assert!(cooldowns.try_acquire("C1/1.0", interval));
+    }
+
+    #[test]
+    fn cooldowns_expire_after_the_interval() {
+        let cooldowns = Cooldowns::default();
+        let interval = Duration::from_millis(20);
+        assert!(cooldowns.try_acquire("C1", interval));
+        assert!(!cooldowns.try_acquire("C1", interval));
+        std::thread::sleep(interval);
+        assert!(cooldowns.try_acquire("C1", interval));
+    }
+
+    #[test]
+    fn leaky_bucket_refuses_calls_once_full_until_it_drains() {
+        let bucket = LeakyBucket::per_minute(3);
+        assert!((0..3).all(|_| bucket.try_acquire()));
+        assert!(!bucket.try_acquire());
+
+        // Drains one call every 10ms.
+        let bucket = LeakyBucket::per_minute(6000);
+        let burst = (0..7000).filter(|_| bucket.try_acquire()).count();
+        assert!((6000..6100).contains(&burst), "{}", burst);
+        std::thread::sleep(Duration::from_millis(50));
+        let drained = (0..1000).filter(|_| bucket.try_acquire()).count();
+        assert!((1..1000).contains(&drained), "{}", drained);
+    }
+}